@@ -1,28 +1,60 @@
+//! The exploit input and output shared by the host and the guest, and the evm setup that
+//! runs the exploit in both. The crate has no features, the host and the guest build it the
+//! same way, with revm on `std` and `serde`. The [revm] and [alloy_primitives] types of its
+//! api are re-exported so an embedder doesn't have to pin matching versions.
+
+pub use alloy_primitives;
+pub use revm;
+
 use std::collections::BTreeMap as Map;
 use alloy_primitives::{address, bytes, Bytes};
 use revm::{
-    db::DatabaseRef, primitives:: {
-        AccountInfo, Address, Bytecode, ResultAndState, SpecId, State, TransactTo, B256, U256,
-        BlockEnv
-    }, Evm
+    db::{CacheDB, DatabaseRef}, primitives:: {
+        AccountInfo, Address, Bytecode, EVMError, ExecutionResult, Log, ResultAndState, SpecId, State, TransactTo,
+        B256, U256, BlockEnv, Env, TxEnv, GAS_PER_BLOB, KECCAK_EMPTY
+    }, Database, DatabaseCommit, Evm
 };
 use serde::{Deserialize, Serialize};
 
 
-#[derive(Default, Clone, Deserialize, Serialize)]
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
 pub struct AccountStorage {
     pub info: AccountInfo,
     pub storage: Map<U256, U256>,
 }
 
 
-#[derive(Default, Clone, Deserialize, Serialize)]
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
 pub struct MemDB {
     pub accounts: Map<Address, AccountStorage>,
     pub block_hashes: Vec<(u64, B256)>,
 }
 
+impl MemDB {
+    /// Number of accounts, storage slots and block hashes in the db
+    pub fn state_entries(&self) -> usize {
+        self.accounts.values().map(|account| 1 + account.storage.len()).sum::<usize>()
+            + self.block_hashes.len()
+    }
+
+    /// The first account whose code doesn't hash to its `code_hash`. The code is served with
+    /// the account info, `code_by_hash_ref` can't look it up, so such an account would fail
+    /// the guest at the first call to it. Only meaningful for an input, the guest commits
+    /// the poc account without its code.
+    pub fn account_with_missing_code(&self) -> Option<Address> {
+        self.accounts.iter().find_map(|(address, account)| {
+            let code_hash = match &account.info.code {
+                Some(code) => code.hash_slow(),
+                None => KECCAK_EMPTY,
+            };
+            (code_hash != account.info.code_hash).then_some(*address)
+        })
+    }
+}
+
 
+/// A read of an account, slot or block hash missing from the db is an error rather than
+/// empty state, so a witness that leaves out what the exploit reads can't prove anything.
 impl DatabaseRef for MemDB {
     type Error = ();
 
@@ -67,7 +99,7 @@ impl DatabaseRef for MemDB {
 
     // History related
     fn block_hash_ref(&self, number: U256) -> Result<B256, Self::Error> {
-        let block_no: u64 = number.try_into().unwrap();
+        let block_no: u64 = number.try_into().map_err(|_| ())?;
         let entry = self.block_hashes.iter()
             .find(|(k, _)| *k == block_no);
         match entry {
@@ -89,37 +121,325 @@ pub const DEFAULT_CONTRACT_ADDRESS: Address = address!("412049F92065a2597458c4cE
 /// func exploit()
 pub const CALL_EXPLOIT_DATA: Bytes = bytes!("63d9b770");
 
-pub const DEFAULT_GAS_LIMIT: u64 = 15_000_000;
+/// The gas limit of the exploit tx follows the block gas limit, capped at this value.
+pub const MAX_GAS_LIMIT: u64 = 300_000_000;
 
 
-#[derive(Deserialize, Serialize)]
+/// A tx applied on top of the fork before the exploit, e.g. a pending tx the exploit
+/// will be bundled after.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SetupTx {
+    pub from: Address,
+    pub to: Address,
+    #[serde(default)]
+    pub data: Bytes,
+    #[serde(default)]
+    pub value: U256,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct ExploitInput {
     pub db: MemDB,
     pub block_env: BlockEnv,
     pub spec_id: SpecId, 
+    /// The chain id returned by the `CHAINID` opcode
+    pub chain_id: u64,
+    /// The effective gas price of the exploit tx
+    pub gas_price: U256,
+    /// The gas limit of the exploit tx
+    pub gas_limit: u64,
+    /// The blob versioned hashes of the exploit tx, read by `BLOBHASH`
+    pub blob_hashes: Vec<B256>,
+    pub max_fee_per_blob_gas: Option<U256>,
+    /// Run in order before the exploit tx, `db` holds the state before them
+    pub setup_txs: Vec<SetupTx>,
+    /// The exploit proves a revert, e.g. that an invariant holds, instead of succeeding
+    pub expect_revert: bool,
+    /// The calldata of the exploit tx, [CALL_EXPLOIT_DATA] unless another entrypoint is called
+    pub calldata: Bytes,
+}
+
+impl ExploitInput {
+    /// The balance of [DEFAULT_CALLER] before the exploit, it covers the max gas fee of the
+    /// exploit tx.
+    pub fn caller_balance(&self) -> U256 {
+        let blob_fee = self.max_fee_per_blob_gas.unwrap_or_default()
+            * U256::from(GAS_PER_BLOB * self.blob_hashes.len() as u64);
+        self.gas_price * U256::from(self.gas_limit) + blob_fee
+    }
 }
 
 
-#[derive(Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct ExploitOutput {
     pub input: ExploitInput,
+    /// The gas paid by the exploit tx, refunds are already deducted
     pub gas_used: u64,
+    pub gas_refunded: u64,
+    pub logs: Vec<Log>,
     pub state: State,
+    /// The revert data of the exploit tx, [Some] exactly when the input expects a revert
+    pub revert: Option<Bytes>,
 }
 
-pub fn sim_exploit(input: &ExploitInput) -> ResultAndState {
+/// The revert data to commit when the outcome of the exploit tx is the one the input
+/// expects, a success or with [ExploitInput::expect_revert] a revert. Fails otherwise.
+pub fn exploit_outcome(input: &ExploitInput, result: &ExecutionResult) -> Result<Option<Bytes>, ()> {
+    match (result, input.expect_revert) {
+        (ExecutionResult::Success { .. }, false) => Ok(None),
+        (ExecutionResult::Revert { output, .. }, true) => Ok(Some(output.clone())),
+        _ => Err(()),
+    }
+}
+
+/// The gas refunded to the caller, zero unless the tx succeeded.
+pub fn gas_refunded(result: &ExecutionResult) -> u64 {
+    match result {
+        ExecutionResult::Success { gas_refunded, .. } => *gas_refunded,
+        _ => 0,
+    }
+}
+
+/// Fills the tx env with the exploit tx, shared by the host and the guest.
+pub fn fill_exploit_tx_env(tx: &mut TxEnv, input: &ExploitInput) {
+    tx.caller = DEFAULT_CALLER;
+    tx.transact_to = TransactTo::Call(DEFAULT_CONTRACT_ADDRESS);
+    tx.data = input.calldata.clone();
+    tx.value = U256::ZERO;
+    tx.gas_limit = input.gas_limit;
+    tx.gas_price = input.gas_price;
+    tx.blob_hashes = input.blob_hashes.clone();
+    tx.max_fee_per_blob_gas = input.max_fee_per_blob_gas;
+}
+
+/// The env every run of the exploit uses, so the preflight, the witness replay and the
+/// guest can't drift apart.
+pub fn exploit_env(input: &ExploitInput) -> Box<Env> {
+    let mut env = Env::default();
+    env.cfg.chain_id = input.chain_id;
+    env.block = input.block_env.clone();
+    fill_exploit_tx_env(&mut env.tx, input);
+    fill_chain_env(&mut env, input);
+    Box::new(env)
+}
+
+/// Sets the env fields an L2 needs on top of the mainnet env
+pub type ChainEnvHook = fn(&mut Env, &ExploitInput);
+
+/// The env hooks of the L2s, by chain id. Mainnet and the chains not listed have none.
+pub const CHAIN_ENV_HOOKS: &[(u64, ChainEnvHook)] = &[(10, fill_op_stack_env), (8453, fill_op_stack_env)];
+
+/// Sets the env fields specific to the chain of the input, mainnet has none.
+pub fn fill_chain_env(env: &mut Env, input: &ExploitInput) {
+    fill_chain_env_with(env, input, CHAIN_ENV_HOOKS)
+}
+
+fn fill_chain_env_with(env: &mut Env, input: &ExploitInput, hooks: &[(u64, ChainEnvHook)]) {
+    if let Some((_, hook)) = hooks.iter().find(|(chain_id, _)| *chain_id == input.chain_id) {
+        hook(env, input)
+    }
+}
+
+/// The env of the OP stack chains (OP mainnet, Base). Their L1 block info is read from the
+/// `L1Block` predeploy, which the preflight fetches like any other storage. Charging the L1
+/// data fee of the tx still needs the enveloped tx in [ExploitInput] and revm's `optimism`
+/// feature in the host and the guest, until then the env is the mainnet one.
+pub fn fill_op_stack_env(_env: &mut Env, _input: &ExploitInput) {}
+
+/// Runs the setup txs of the input and commits their effects to `db`. They pay no gas, so
+/// their senders only need the ether they send, and each of them must succeed.
+pub fn apply_setup_txs<DB: Database + DatabaseCommit>(
+    db: &mut DB,
+    input: &ExploitInput,
+) -> Result<(), EVMError<DB::Error>> {
+    for (i, setup) in input.setup_txs.iter().enumerate() {
+        let mut env = exploit_env(input);
+        env.tx = TxEnv {
+            caller: setup.from,
+            transact_to: TransactTo::Call(setup.to),
+            data: setup.data.clone(),
+            value: setup.value,
+            gas_limit: input.gas_limit,
+            gas_price: U256::ZERO,
+            ..Default::default()
+        };
+        let mut evm = Evm::builder()
+            .with_db(&mut *db)
+            .with_env(env)
+            .with_spec_id(input.spec_id)
+            .build();
+        let ResultAndState { result, state } = evm.transact_preverified()?;
+        drop(evm);
+        if !result.is_success() {
+            return Err(EVMError::Custom(format!("setup tx {} failed: {:?}", i, result)));
+        }
+        db.commit(state);
+    }
+    Ok(())
+}
+
+/// Runs the setup txs then the exploit tx of the input over its db, the way the guest does.
+/// Fails when the exploit reads state the db doesn't hold or a setup tx fails.
+pub fn try_sim_exploit(input: &ExploitInput) -> Result<ResultAndState, EVMError<()>> {
+    let mut db = CacheDB::new(&input.db);
+    apply_setup_txs(&mut db, input)?;
     let mut evm = Evm::builder()
-        .with_ref_db(&input.db)
+        .with_db(&mut db)
+        .with_env(exploit_env(input))
         .with_spec_id(input.spec_id)
-        .with_block_env(input.block_env.clone())
-        .modify_tx_env(|tx| {
-            tx.caller = DEFAULT_CALLER;
-            tx.transact_to = TransactTo::Call(DEFAULT_CONTRACT_ADDRESS);
-            tx.data = CALL_EXPLOIT_DATA;
-            tx.value = U256::ZERO;
-            tx.gas_limit = DEFAULT_GAS_LIMIT;
-        })
         .build();
 
-    evm.transact().unwrap()
-}
\ No newline at end of file
+    evm.transact()
+}
+
+/// [try_sim_exploit] for the guest, which has no way to report an error but to panic. The
+/// host calls [try_sim_exploit].
+///
+/// ```
+/// use bridge::{sim_exploit, AccountStorage, ExploitInput, MemDB, CALL_EXPLOIT_DATA, DEFAULT_CALLER, DEFAULT_CONTRACT_ADDRESS};
+/// use bridge::alloy_primitives::{bytes, U256};
+/// use bridge::revm::primitives::{AccountInfo, BlockEnv, Bytecode, SpecId};
+///
+/// // PUSH1 1 PUSH1 0 SSTORE STOP
+/// let code = Bytecode::new_raw(bytes!("600160005500"));
+/// let mut input = ExploitInput {
+///     db: MemDB::default(),
+///     block_env: BlockEnv::default(),
+///     spec_id: SpecId::SHANGHAI,
+///     chain_id: 1,
+///     gas_price: U256::from(1),
+///     gas_limit: 100_000,
+///     blob_hashes: Vec::new(),
+///     max_fee_per_blob_gas: None,
+///     setup_txs: Vec::new(),
+///     expect_revert: false,
+///     calldata: CALL_EXPLOIT_DATA,
+/// };
+/// let caller = AccountInfo { balance: input.caller_balance(), nonce: 1, ..Default::default() };
+/// let mut poc = AccountStorage { info: AccountInfo::new(U256::ZERO, 1, code.hash_slow(), code), ..Default::default() };
+/// poc.storage.insert(U256::ZERO, U256::ZERO);
+/// input.db.accounts.insert(DEFAULT_CALLER, AccountStorage { info: caller, ..Default::default() });
+/// input.db.accounts.insert(DEFAULT_CONTRACT_ADDRESS, poc);
+/// input.db.accounts.insert(input.block_env.coinbase, AccountStorage::default());
+///
+/// let result = sim_exploit(&input);
+/// assert!(result.result.is_success());
+/// assert_eq!(result.state[&DEFAULT_CONTRACT_ADDRESS].storage[&U256::ZERO].present_value, U256::from(1));
+/// ```
+pub fn sim_exploit(input: &ExploitInput) -> ResultAndState {
+    try_sim_exploit(input).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(info: AccountInfo) -> AccountStorage {
+        AccountStorage { info, storage: Map::new() }
+    }
+
+    /// An input whose poc runs `code`, with the accounts a plain call reads
+    fn exploit_input(code: Bytes) -> ExploitInput {
+        let code = Bytecode::new_raw(code);
+        let mut input = ExploitInput {
+            db: MemDB::default(),
+            block_env: BlockEnv::default(),
+            spec_id: SpecId::SHANGHAI,
+            chain_id: 1,
+            gas_price: U256::from(1),
+            gas_limit: 100_000,
+            blob_hashes: Vec::new(),
+            max_fee_per_blob_gas: None,
+            setup_txs: Vec::new(),
+            expect_revert: false,
+            calldata: CALL_EXPLOIT_DATA,
+        };
+        let caller = AccountInfo { balance: input.caller_balance(), nonce: 1, ..Default::default() };
+        input.db.accounts.insert(DEFAULT_CALLER, account(caller));
+        input.db.accounts.insert(DEFAULT_CONTRACT_ADDRESS, account(AccountInfo::new(U256::ZERO, 1, code.hash_slow(), code)));
+        input.db.accounts.insert(input.block_env.coinbase, account(AccountInfo::default()));
+        input
+    }
+
+    #[test]
+    fn sim_exploit_runs_twice_over_the_same_db() {
+        // PUSH1 1 PUSH1 0 SSTORE STOP
+        let mut input = exploit_input(bytes!("600160005500"));
+        input.db.accounts.get_mut(&DEFAULT_CONTRACT_ADDRESS).unwrap().storage.insert(U256::ZERO, U256::ZERO);
+        let db = input.db.clone();
+
+        let first = sim_exploit(&input);
+        let second = sim_exploit(&input);
+        assert!(first.result.is_success());
+        assert_eq!(first, second);
+        assert_eq!(first.state[&DEFAULT_CONTRACT_ADDRESS].storage[&U256::ZERO].present_value, U256::from(1));
+        // the runs commit to their own cache, the db is untouched
+        assert_eq!(input.db, db);
+    }
+
+    #[test]
+    fn only_a_configured_l2_runs_its_env_hook() {
+        fn mark(env: &mut Env, _input: &ExploitInput) {
+            env.cfg.chain_id = 0;
+        }
+        let hooks: &[(u64, ChainEnvHook)] = &[(10, mark)];
+
+        // mainnet keeps the plain env
+        let input = exploit_input(bytes!("00"));
+        let env = exploit_env(&input);
+        assert_eq!(env.cfg.chain_id, 1);
+        assert_eq!(env.block, input.block_env);
+        assert_eq!(env.tx.data, input.calldata);
+        let mut hooked = env.clone();
+        fill_chain_env_with(&mut hooked, &input, hooks);
+        assert_eq!(hooked, env);
+
+        let mut l2 = exploit_input(bytes!("00"));
+        l2.chain_id = 10;
+        let mut env = exploit_env(&l2);
+        assert_eq!(env.cfg.chain_id, 10);
+        fill_chain_env_with(&mut env, &l2, hooks);
+        assert_eq!(env.cfg.chain_id, 0);
+        assert!(CHAIN_ENV_HOOKS.iter().any(|(chain_id, _)| *chain_id == 10));
+    }
+
+    #[test]
+    fn sim_of_a_db_without_the_caller_fails() {
+        let mut input = exploit_input(bytes!("00"));
+        input.db.accounts.remove(&DEFAULT_CALLER);
+        assert_eq!(try_sim_exploit(&input), Err(EVMError::Database(())));
+    }
+
+    #[test]
+    fn withdrawal_reverts_as_expected() {
+        // PUSH1 0x2a PUSH1 0 MSTORE PUSH1 1 PUSH1 31 REVERT, a withdrawal the vault refuses
+        let mut input = exploit_input(bytes!("602a6000526001601ffd"));
+        input.expect_revert = true;
+        let result = sim_exploit(&input).result;
+        assert_eq!(exploit_outcome(&input, &result), Ok(Some(bytes!("2a"))));
+
+        input.expect_revert = false;
+        assert_eq!(exploit_outcome(&input, &result), Err(()));
+    }
+
+    #[test]
+    fn accounts_with_their_code_pass_the_code_check() {
+        let code = Bytecode::new_raw(bytes!("6000"));
+        let mut db = MemDB::default();
+        db.accounts.insert(DEFAULT_CONTRACT_ADDRESS, account(AccountInfo::new(U256::ZERO, 1, code.hash_slow(), code)));
+        db.accounts.insert(DEFAULT_CALLER, account(AccountInfo::default()));
+        assert_eq!(db.account_with_missing_code(), None);
+    }
+
+    #[test]
+    fn account_without_its_code_fails_the_code_check() {
+        let code = Bytecode::new_raw(bytes!("6000"));
+        let mut db = MemDB::default();
+        db.accounts.insert(DEFAULT_CALLER, account(AccountInfo::default()));
+        db.accounts.insert(DEFAULT_CONTRACT_ADDRESS, account(AccountInfo {
+            code: None,
+            ..AccountInfo::new(U256::ZERO, 1, code.hash_slow(), code)
+        }));
+        assert_eq!(db.account_with_missing_code(), Some(DEFAULT_CONTRACT_ADDRESS));
+    }
+}
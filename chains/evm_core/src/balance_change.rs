@@ -1,16 +1,17 @@
-use alloy_primitives::address;
-use alloy_sol_types::SolCall;
+use alloy_primitives::{address, I256};
+use alloy_sol_types::{SolCall, SolEvent};
 use anyhow::{bail, Result};
 use bridge::DEFAULT_CONTRACT_ADDRESS;
 use revm::{
     db::CacheDB,
-    primitives::{AccountInfo, Address, Bytecode, ExecutionResult, State, TransactTo, B256, KECCAK_EMPTY, U256},
+    primitives::{AccountInfo, Address, Bytecode, ExecutionResult, Log, State, TransactTo, B256, KECCAK_EMPTY, U256},
     DatabaseCommit, DatabaseRef, Evm,
 };
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{collections::{BTreeMap, BTreeSet, HashMap}, str::FromStr};
 
-use crate::helper_contract::{Helper, BALANCE_CHECKER_CONTRACT_CODE};
+use crate::helper_contract::{Helper, IERC20, BALANCE_CHECKER_CONTRACT_CODE};
+use crate::utils::{explain_evm_error, parse_ether_value};
 
 pub struct SafeStorageDB<'a, T: DatabaseRef> {
     db: &'a T,
@@ -68,7 +69,7 @@ impl<'a, T: DatabaseRef> DatabaseRef for SafeStorageDB<'a, T> {
     }
 }
 
-#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct AssetChange {
     pub address: Address,
     pub token: Address,
@@ -104,10 +105,7 @@ pub fn batch_get_token_balance<T: DatabaseRef>(
 
     let result = match evm.transact_preverified() {
         Ok(result) => result.result,
-        Err(err) => {
-            println!("Failed to execute transaction: {:#?}", err);
-            bail!("Failed to execute transaction")
-        },
+        Err(err) => bail!("Failed to query token balances: {}", explain_evm_error(&err)),
     };
     let ExecutionResult::Success { output, .. } = result else {
         bail!("Transaction failed");
@@ -153,3 +151,233 @@ pub fn compute_asset_change<D: DatabaseRef>(
     }
     Ok(result)
 }
+
+/// The signed net change of an account in a token.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenGain {
+    pub address: Address,
+    pub token: Address,
+    pub amount: I256,
+}
+
+/// Sums the asset change into a net gain per account and token. When `wrapped_native` is
+/// set, that token is counted as ether (`Address::ZERO`) so wrapping and unwrapping doesn't
+/// split the gain in two.
+pub fn compute_net_gain(asset_change: &[AssetChange], wrapped_native: Option<Address>) -> Vec<TokenGain> {
+    let mut gains: BTreeMap<(Address, Address), I256> = BTreeMap::new();
+    for change in asset_change {
+        let token = match wrapped_native {
+            Some(wrapped) if wrapped == change.token => Address::ZERO,
+            _ => change.token,
+        };
+        let delta = I256::from_raw(change.to) - I256::from_raw(change.from);
+        *gains.entry((change.address, token)).or_default() += delta;
+    }
+    gains
+        .into_iter()
+        .filter(|(_, amount)| !amount.is_zero())
+        .map(|((address, token), amount)| TokenGain { address, token, amount })
+        .collect()
+}
+
+/// Cross-checks the asset change of `accounts` against the erc20 `Transfer` events of the
+/// exploit. Rebasing or fee-on-transfer tokens move balances in ways the events don't
+/// tell, every such mismatch is returned as a warning. Ether is not checked.
+pub fn reconcile_transfers(
+    accounts: &[Address],
+    asset_change: &[AssetChange],
+    logs: &[Log],
+) -> Vec<String> {
+    let mut transferred: BTreeMap<(Address, Address), I256> = BTreeMap::new();
+    for log in logs {
+        // erc721 shares the signature with an indexed token id, it fails to decode here
+        let Ok(transfer) = IERC20::Transfer::decode_log_data(&log.data, true) else {
+            continue;
+        };
+        let value = I256::from_raw(transfer.value);
+        *transferred.entry((transfer.from, log.address)).or_default() -= value;
+        *transferred.entry((transfer.to, log.address)).or_default() += value;
+    }
+
+    let mut changed: BTreeMap<(Address, Address), I256> = BTreeMap::new();
+    for change in asset_change.iter().filter(|change| change.token != Address::ZERO) {
+        changed.insert(
+            (change.address, change.token),
+            I256::from_raw(change.to) - I256::from_raw(change.from),
+        );
+    }
+
+    let keys: BTreeSet<(Address, Address)> = changed.keys().chain(transferred.keys()).cloned().collect();
+    let mut warnings = Vec::new();
+    for (address, token) in keys {
+        if !accounts.contains(&address) {
+            continue;
+        }
+        let balance_delta = changed.get(&(address, token)).copied().unwrap_or_default();
+        let transfer_delta = transferred.get(&(address, token)).copied().unwrap_or_default();
+        if balance_delta != transfer_delta {
+            warnings.push(format!(
+                "balance change of {} in {} not explained by transfers: balance {}, transfers {}",
+                address, token, balance_delta, transfer_delta
+            ));
+        }
+    }
+    warnings
+}
+
+/// How a [GainExpectation] compares the gain with its amount.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    Ge,
+    Le,
+    Gt,
+    Lt,
+    Eq,
+}
+
+/// An expected gain of an account in a token, parsed from `<token>:<cmp><amount>`, or
+/// `<cmp><amount>` for ether, e.g. `0xdac17f958d2ee523a2206206994597c13d831ec7:>=1000000` or
+/// `>1ether`. The amount accepts the same units as `--deal`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GainExpectation {
+    pub token: Address,
+    pub comparison: Comparison,
+    pub amount: U256,
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("{0}")]
+pub struct ParseExpectationError(String);
+
+impl FromStr for GainExpectation {
+    type Err = ParseExpectationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (token, expectation) = match s.rsplit_once(':') {
+            Some((token, expectation)) => {
+                let token = Address::from_str(token.trim())
+                    .map_err(|_x| ParseExpectationError("error `<token>`".to_string()))?;
+                (token, expectation.trim())
+            }
+            None => (Address::ZERO, s.trim()),
+        };
+        let (comparison, amount) = [
+            (">=", Comparison::Ge),
+            ("<=", Comparison::Le),
+            ("==", Comparison::Eq),
+            (">", Comparison::Gt),
+            ("<", Comparison::Lt),
+            ("=", Comparison::Eq),
+        ]
+        .into_iter()
+        .find_map(|(op, comparison)| expectation.strip_prefix(op).map(|amount| (comparison, amount)))
+        .ok_or_else(|| {
+            ParseExpectationError(
+                "expectation format must be `<token>:<cmp><amount>` or `<cmp><amount>`, cmp is one of >=, <=, >, <, =".to_string(),
+            )
+        })?;
+        let amount = parse_ether_value(amount.trim())
+            .map_err(|_x| ParseExpectationError("error `<amount>`".to_string()))?;
+        Ok(GainExpectation { token, comparison, amount })
+    }
+}
+
+impl GainExpectation {
+    /// Whether the gain of `address` in the token, summed over the asset change, meets the
+    /// expectation. A token the account didn't touch has gained zero.
+    pub fn check(&self, address: Address, asset_change: &[AssetChange]) -> bool {
+        let gain: I256 = asset_change
+            .iter()
+            .filter(|change| change.address == address && change.token == self.token)
+            .fold(I256::ZERO, |gain, change| gain + I256::from_raw(change.to) - I256::from_raw(change.from));
+        let amount = I256::from_raw(self.amount);
+        match self.comparison {
+            Comparison::Ge => gain >= amount,
+            Comparison::Le => gain <= amount,
+            Comparison::Gt => gain > amount,
+            Comparison::Lt => gain < amount,
+            Comparison::Eq => gain == amount,
+        }
+    }
+}
+
+/// The `decimals()` and `symbol()` of a token, [None] if it doesn't answer them.
+pub fn token_metadata<T: DatabaseRef>(db: &T, token: Address) -> Option<(u8, String)>
+where <T as DatabaseRef>::Error: std::fmt::Debug {
+    let call = |data: Vec<u8>| -> Option<Vec<u8>> {
+        let mut evm = Evm::builder()
+            .with_ref_db(SafeStorageDB::new(db))
+            .modify_tx_env(|tx| {
+                tx.caller = address!("1000000000000000000000000000000000000000");
+                tx.transact_to = TransactTo::Call(token);
+                tx.data = data.into();
+            })
+            .build();
+        match evm.transact_preverified().ok()?.result {
+            ExecutionResult::Success { output, .. } => Some(output.into_data().to_vec()),
+            _ => None,
+        }
+    };
+    let decimals = IERC20::decimalsCall::abi_decode_returns(&call(IERC20::decimalsCall {}.abi_encode())?, false).ok()?._0;
+    let symbol = IERC20::symbolCall::abi_decode_returns(&call(IERC20::symbolCall {}.abi_encode())?, false).ok()?._0;
+    Some((decimals, symbol))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USDC: Address = address!("a0b86991c6218b36c1d19d4a2e9eb0ce3606eb48");
+
+    fn change(token: Address, from: u128, to: u128) -> AssetChange {
+        AssetChange { address: DEFAULT_CONTRACT_ADDRESS, token, from: U256::from(from), to: U256::from(to) }
+    }
+
+    #[test]
+    fn gain_expectations_parse_and_check_the_gain() {
+        let usdc: GainExpectation = "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48:>=1000000".parse().unwrap();
+        assert_eq!(usdc, GainExpectation { token: USDC, comparison: Comparison::Ge, amount: U256::from(1_000_000) });
+        let ether: GainExpectation = ">1ether".parse().unwrap();
+        assert_eq!(ether, GainExpectation { token: Address::ZERO, comparison: Comparison::Gt, amount: U256::from(10u128.pow(18)) });
+        assert_eq!("<=5".parse::<GainExpectation>().unwrap().comparison, Comparison::Le);
+        assert_eq!("==5".parse::<GainExpectation>().unwrap().comparison, Comparison::Eq);
+        assert_eq!("=5".parse::<GainExpectation>().unwrap().comparison, Comparison::Eq);
+
+        assert_eq!("0xusdc:>=1".parse::<GainExpectation>().unwrap_err().to_string(), "error `<token>`");
+        assert_eq!(">=lots".parse::<GainExpectation>().unwrap_err().to_string(), "error `<amount>`");
+        assert!("1000000".parse::<GainExpectation>().is_err());
+
+        // the poc gained 0.7 usdc and 0.5 ether
+        let asset_change = [change(USDC, 500_000, 1_200_000), change(Address::ZERO, 10u128.pow(18), 15 * 10u128.pow(17))];
+        assert!(!usdc.check(DEFAULT_CONTRACT_ADDRESS, &asset_change));
+        assert!(!ether.check(DEFAULT_CONTRACT_ADDRESS, &asset_change));
+        assert!("0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48:>=700000".parse::<GainExpectation>().unwrap()
+            .check(DEFAULT_CONTRACT_ADDRESS, &asset_change));
+        assert!("<1ether".parse::<GainExpectation>().unwrap().check(DEFAULT_CONTRACT_ADDRESS, &asset_change));
+        // an account the exploit didn't touch gained nothing
+        assert!(!"0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48:>0".parse::<GainExpectation>().unwrap()
+            .check(Address::repeat_byte(1), &asset_change));
+    }
+
+    #[test]
+    fn fee_on_transfer_is_not_explained_by_the_transfers() {
+        let pool = Address::repeat_byte(1);
+        let transfer = |from: Address, to: Address, value: u64| Log {
+            address: USDC,
+            data: IERC20::Transfer { from, to, value: U256::from(value) }.encode_log_data(),
+        };
+        // the pool sends 100, the token keeps a fee of 2 and the poc receives 98
+        let logs = [transfer(pool, DEFAULT_CONTRACT_ADDRESS, 100)];
+        let asset_change = [change(USDC, 0, 98), AssetChange { address: pool, token: USDC, from: U256::from(1000), to: U256::from(900) }];
+
+        let warnings = reconcile_transfers(&[DEFAULT_CONTRACT_ADDRESS, pool], &asset_change, &logs);
+        assert_eq!(warnings, vec![format!(
+            "balance change of {} in {} not explained by transfers: balance 98, transfers 100",
+            DEFAULT_CONTRACT_ADDRESS, USDC
+        )]);
+
+        // a plain token moves what it logs
+        let asset_change = [change(USDC, 0, 100), asset_change[1].clone()];
+        assert!(reconcile_transfers(&[DEFAULT_CONTRACT_ADDRESS, pool], &asset_change, &logs).is_empty());
+    }
+}
@@ -1,10 +1,13 @@
 use alloy_primitives::{Address, U256, BlockHash, BlockNumber, B256, B64, Bloom, Bytes};
-use alloy_rpc_types::Header;
+use alloy_provider::{Network, Provider};
+use alloy_rpc_types::{BlockId, Header};
+use alloy_transport::Transport;
 use revm::primitives::BlockEnv;
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use serde::{Serialize, Deserialize};
+use std::{collections::BTreeMap as Map, fs, future::Future, path::Path, time::{Duration, SystemTime, UNIX_EPOCH}};
 
-#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
 pub struct BlockHeader {
     /// Hash of the parent
     pub parent_hash: BlockHash,
@@ -56,7 +59,7 @@ impl BlockHeader {
         block_env.coinbase = self.author;
         block_env.difficulty = self.difficulty;
         block_env.gas_limit = U256::from(self.gas_limit);
-        // block_env.basefee = self.base_fee_per_gas;
+        block_env.basefee = self.base_fee_per_gas;
         block_env.prevrandao = Some(self.mix_hash);
         if let Some(excess_blob_gas) = self.excess_blob_gas {
             block_env.set_blob_excess_gas_and_price(excess_blob_gas);
@@ -65,6 +68,17 @@ impl BlockHeader {
     }
 }
 
+/// Loads a header saved as the json of `eth_getBlockByNumber`, the block fields other than
+/// the header are ignored.
+pub fn load_header(path: impl AsRef<Path>) -> Result<BlockHeader> {
+    let path = path.as_ref();
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read header file {:?}", path))?;
+    let header: Header = serde_json::from_str(&content)
+        .with_context(|| format!("Malformed header file {:?}", path))?;
+    header.try_into()
+}
+
 impl TryFrom<Header> for BlockHeader {
     type Error = anyhow::Error;
 
@@ -94,3 +108,88 @@ impl TryFrom<Header> for BlockHeader {
     }
 }
 
+
+/// The last block whose timestamp is not after `timestamp`, found by binary search over the
+/// block numbers. Past resolutions are kept in the json at `cache_path`, a map of timestamp to
+/// block number, once a later block exists the answer can't change. `timeout` bounds every
+/// rpc request of the search.
+pub async fn block_at_timestamp<T: Transport + Clone, N: Network, P: Provider<T, N>>(
+    provider: &P,
+    timestamp: u64,
+    cache_path: Option<&Path>,
+    timeout: Option<Duration>,
+) -> Result<u64> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    if timestamp > now {
+        bail!("The timestamp {} is in the future, it is now {}", timestamp, now)
+    }
+    let mut cache: Map<u64, u64> = match cache_path {
+        Some(path) if path.exists() => serde_json::from_str(&fs::read_to_string(path)?)
+            .with_context(|| format!("Malformed timestamp cache {:?}", path))?,
+        _ => Map::new(),
+    };
+    if let Some(block_number) = cache.get(&timestamp) {
+        return Ok(*block_number)
+    }
+
+    let block_timestamp = |number: u64| async move {
+        let block = with_timeout("rpc request", timeout, provider.get_block(BlockId::number(number), false)).await??
+            .with_context(|| format!("could not find block {}", number))?;
+        anyhow::Ok(block.header.timestamp)
+    };
+    let latest = with_timeout("rpc request", timeout, provider.get_block_number()).await??;
+    let genesis_timestamp = block_timestamp(0).await?;
+    if timestamp < genesis_timestamp {
+        bail!("The timestamp {} is before the genesis block at {}", timestamp, genesis_timestamp)
+    }
+    if block_timestamp(latest).await? <= timestamp {
+        // a later block may still be mined before the timestamp, don't cache it
+        return Ok(latest)
+    }
+    // invariant: the block `low` is not after the timestamp, the block `high + 1` is
+    let (mut low, mut high) = (0, latest - 1);
+    while low < high {
+        let mid = low + (high - low + 1) / 2;
+        if block_timestamp(mid).await? <= timestamp {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+
+    if let Some(path) = cache_path {
+        cache.insert(timestamp, low);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_string(&cache)?)?;
+    }
+    Ok(low)
+}
+
+/// Awaits the future of a phase, failing if it takes longer than the timeout.
+pub async fn with_timeout<F: Future>(phase: &str, timeout: Option<Duration>, future: F) -> Result<F::Output> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| anyhow!("{} timed out after {:?}", phase, timeout)),
+        None => Ok(future.await),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::RuntimeOrHandle;
+
+    #[test]
+    fn a_phase_that_never_ends_times_out() {
+        let runtime = RuntimeOrHandle::new();
+        let err = runtime
+            .block_on(with_timeout("rpc request", Some(Duration::from_millis(10)), std::future::pending::<()>()))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "rpc request timed out after 10ms");
+        assert_eq!(runtime.block_on(with_timeout("rpc request", Some(Duration::from_secs(1)), async { 7 })).unwrap(), 7);
+        assert_eq!(runtime.block_on(with_timeout("rpc request", None, async { 7 })).unwrap(), 7);
+    }
+}
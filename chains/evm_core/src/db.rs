@@ -1,39 +1,121 @@
-use alloy_primitives::{Address, B256, U256};
+use alloy_primitives::{address, Address, B256, U256};
 use alloy_provider::{Network, Provider};
+use alloy_rpc_types::{AccessList, AccessListItem};
 use alloy_transport::Transport;
 
-use anyhow::{Result, Context};
+use anyhow::{anyhow, Result, Context};
 use bridge::{MemDB, AccountStorage};
 use log::{debug, warn};
-use std::collections::BTreeMap as Map;
-use revm::primitives::{AccountInfo, Bytecode, SpecId};
+use parking_lot::RwLock;
+use std::collections::{BTreeMap as Map, BTreeSet};
+use revm::primitives::{Account, AccountInfo, Bytecode, HashMap, SpecId};
 pub use revm::{DatabaseRef, Database, DatabaseCommit};
 use serde::{Deserialize, Serialize};
-use std::cell::RefCell;
-use std::{fs, io::BufWriter, path::PathBuf};
-use crate::block::BlockHeader;
+use std::{fs, future::Future, io::BufWriter, path::PathBuf, sync::atomic::{AtomicU64, Ordering}, time::Duration};
+use crate::block::{with_timeout, BlockHeader};
 use crate::utils::RuntimeOrHandle;
 
 
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct ChainSpec {
     pub chain_id: u64,
-    pub spec_id: SpecId,
 }
 
 impl ChainSpec {
     pub fn mainnet() -> Self {
-        Self { chain_id: 1, spec_id: SpecId::SHANGHAI }
+        Self::new(1)
+    }
+
+    pub fn new(chain_id: u64) -> Self {
+        Self { chain_id }
+    }
+
+    /// The hardfork the chain runs at the block, from the London and merge block numbers and
+    /// the Shanghai and Cancun timestamps of the chain. Chains without a known schedule run
+    /// the mainnet rules of the same time.
+    pub fn spec_id(&self, number: u64, timestamp: u64) -> Result<SpecId> {
+        // (london block, merge block, shanghai timestamp, cancun timestamp)
+        let (london, merge, shanghai, cancun) = match self.chain_id {
+            1 => (12_965_000, 15_537_394, 1_681_338_455, 1_710_338_135),
+            11155111 => (0, 1_735_371, 1_677_557_088, 1_706_655_072),
+            17000 => (0, 0, 1_696_000_704, 1_707_305_664),
+            _ => {
+                warn!("the hardforks of chain {} are not known, it runs the mainnet rules", self.chain_id);
+                return Self::mainnet().spec_id(u64::MAX, timestamp)
+            }
+        };
+        let spec_id = if timestamp >= cancun {
+            SpecId::CANCUN
+        } else if timestamp >= shanghai {
+            SpecId::SHANGHAI
+        } else if number >= merge {
+            SpecId::MERGE
+        } else if number >= london {
+            SpecId::LONDON
+        } else {
+            return Err(anyhow!("block {} of chain {} is before London, it is not supported", number, self.chain_id))
+        };
+        Ok(spec_id)
+    }
+
+    /// The canonical wrapped native token of the chain
+    pub fn wrapped_native(&self) -> Option<Address> {
+        match self.chain_id {
+            1 => Some(address!("C02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2")),
+            _ => None,
+        }
     }
 }
 
+impl Default for ChainSpec {
+    fn default() -> Self {
+        Self::mainnet()
+    }
+}
 
-#[derive(Debug, Clone, Eq, Serialize, Deserialize)]
+
+#[derive(Debug, Clone, Default, Eq, Serialize, Deserialize)]
 pub struct BlockchainDbMeta {
     pub chain_spec: ChainSpec,
     pub header: BlockHeader,
 }
 
+impl BlockchainDbMeta {
+    pub fn new(chain_spec: ChainSpec, header: BlockHeader) -> Self {
+        Self { chain_spec, header }
+    }
+
+    /// A meta of mainnet and an empty header, to set with the fluent setters
+    pub fn builder() -> BlockchainDbMetaBuilder {
+        BlockchainDbMetaBuilder::default()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BlockchainDbMetaBuilder {
+    meta: BlockchainDbMeta,
+}
+
+impl BlockchainDbMetaBuilder {
+    pub fn chain_spec(mut self, chain_spec: ChainSpec) -> Self {
+        self.meta.chain_spec = chain_spec;
+        self
+    }
+
+    pub fn chain_id(self, chain_id: u64) -> Self {
+        self.chain_spec(ChainSpec::new(chain_id))
+    }
+
+    pub fn header(mut self, header: BlockHeader) -> Self {
+        self.meta.header = header;
+        self
+    }
+
+    pub fn build(self) -> BlockchainDbMeta {
+        self.meta
+    }
+}
+
 impl PartialEq for BlockchainDbMeta {
     fn eq(&self, other: &Self) -> bool {
         self.chain_spec == other.chain_spec && self.header == other.header
@@ -66,17 +148,53 @@ pub struct JsonBlockCacheData {
     pub block_hashes: Map<u64, B256>,
 }
 
+impl JsonBlockCacheData {
+    /// Extracts the cached rpc data of the accounts, slots and block hashes in `db`. The
+    /// snapshot can replace the rpc when building the same input again.
+    pub fn snapshot(&self, db: &MemDB) -> Self {
+        let mut snapshot = JsonBlockCacheData {
+            meta: self.meta.clone(),
+            accounts: Map::new(),
+            storage: Map::new(),
+            block_hashes: Map::new(),
+        };
+        for (address, account) in db.accounts.iter() {
+            if let Some(info) = self.accounts.get(address) {
+                snapshot.accounts.insert(*address, info.clone());
+            }
+            for index in account.storage.keys() {
+                if let Some(value) = self.storage.get(address).and_then(|s| s.get(index)) {
+                    snapshot.storage.entry(*address).or_default().insert(*index, *value);
+                }
+            }
+        }
+        for (number, _) in db.block_hashes.iter() {
+            if let Some(hash) = self.block_hashes.get(number) {
+                snapshot.block_hashes.insert(*number, *hash);
+            }
+        }
+        snapshot
+    }
+}
+
 /// A [JsonBlockCacheDB] that stores the cached content in a json file
 #[derive(Debug)]
 pub struct JsonBlockCacheDB<T: Transport + Clone, N: Network, P: Provider<T, N>> {
-    /// The provider that's used to fetch data
-    provider: P,
+    /// The provider that's used to fetch data, [None] serves a snapshot and fails on a miss
+    provider: Option<P>,
     /// The runtime that's used to run async tasks
     tokio_handle: RuntimeOrHandle,
     /// If this is a [None] then caching is disabled
     cache_path: Option<PathBuf>,
-    /// Object that's stored in a json file
-    data: RefCell<JsonBlockCacheData>,
+    /// The timeout of a single rpc request, [None] waits forever
+    timeout: Option<Duration>,
+    /// Object that's stored in a json file, guarded so the db can be read from several
+    /// threads
+    data: RwLock<JsonBlockCacheData>,
+    /// The reads served from the cache
+    cache_hits: AtomicU64,
+    /// The reads that went to the rpc
+    rpc_requests: AtomicU64,
     _marker: std::marker::PhantomData<fn() -> (T, N)>,
 }
 
@@ -95,10 +213,59 @@ impl<T: Transport + Clone, N: Network, P: Provider<T, N>> JsonBlockCacheDB<T, N,
             });
         
         Self {
-            provider,
+            provider: Some(provider),
             tokio_handle,
             cache_path,
-            data: RefCell::new(cache),
+            timeout: None,
+            data: RwLock::new(cache),
+            cache_hits: AtomicU64::new(0),
+            rpc_requests: AtomicU64::new(0),
+            _marker: std::marker::PhantomData,
+        }
+    }
+
+    /// Serves the reads from a snapshot exported by [JsonBlockCacheData::snapshot] only,
+    /// a read the snapshot doesn't cover is an error.
+    pub fn from_snapshot(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let data = Self::load_cache(&path)
+            .with_context(|| format!("Failed to load the snapshot {}", path.display()))?;
+        Ok(Self {
+            provider: None,
+            tokio_handle: RuntimeOrHandle::new(),
+            cache_path: None,
+            timeout: None,
+            data: RwLock::new(data),
+            cache_hits: AtomicU64::new(0),
+            rpc_requests: AtomicU64::new(0),
+            _marker: std::marker::PhantomData,
+        })
+    }
+
+    /// A db of the same block with an empty cache and no cache file, every read goes to the
+    /// rpc again. A snapshot db has no rpc, the copy serves the same snapshot.
+    pub fn fresh(&self) -> Self
+    where
+        P: Clone,
+    {
+        let data = self.data.read();
+        let data = match self.provider {
+            Some(_) => JsonBlockCacheData {
+                meta: data.meta.clone(),
+                accounts: Map::new(),
+                storage: Map::new(),
+                block_hashes: Map::new(),
+            },
+            None => data.clone(),
+        };
+        Self {
+            provider: self.provider.clone(),
+            tokio_handle: RuntimeOrHandle::new(),
+            cache_path: None,
+            timeout: self.timeout,
+            data: RwLock::new(data),
+            cache_hits: AtomicU64::new(0),
+            rpc_requests: AtomicU64::new(0),
             _marker: std::marker::PhantomData,
         }
     }
@@ -118,6 +285,30 @@ impl<T: Transport + Clone, N: Network, P: Provider<T, N>> JsonBlockCacheDB<T, N,
         Ok(data)
     }
 
+    /// Sets the timeout of a single rpc request
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    fn provider(&self) -> Result<&P> {
+        self.provider.as_ref().ok_or_else(|| anyhow!("not in the snapshot"))
+    }
+
+    /// Runs an rpc request to completion, failing when it takes longer than the timeout
+    fn fetch<F, R, E>(&self, request: F) -> Result<R>
+    where
+        F: Future<Output = Result<R, E>>,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        self.rpc_requests.fetch_add(1, Ordering::Relaxed);
+        Ok(self.tokio_handle.block_on(with_timeout("rpc request", self.timeout, request))??)
+    }
+
+    /// The reads served from the cache and the reads that went to the rpc so far
+    pub fn read_counts(&self) -> (u64, u64) {
+        (self.cache_hits.load(Ordering::Relaxed), self.rpc_requests.load(Ordering::Relaxed))
+    }
+
     /// Returns `true` if this is a transient cache and nothing will be flushed
     pub fn is_transient(&self) -> bool {
         self.cache_path.is_none()
@@ -134,7 +325,7 @@ impl<T: Transport + Clone, N: Network, P: Provider<T, N>> JsonBlockCacheDB<T, N,
             let _ = fs::File::create(path)
                 .map_err(|e| warn!("Failed to open json cache for writing: {}", e))
                 .and_then(|f| {
-                    serde_json::to_writer(BufWriter::new(f), &self.data)
+                    serde_json::to_writer(BufWriter::new(f), &*self.data.read())
                         .map_err(|e| warn!(target: "cache" ,"Failed to write to json cache: {}", e))
                 });
                 debug!("saved json cache path={:?}", path);
@@ -142,7 +333,7 @@ impl<T: Transport + Clone, N: Network, P: Provider<T, N>> JsonBlockCacheDB<T, N,
     }
 
     pub fn data(&self) -> JsonBlockCacheData{
-        self.data.borrow().clone()
+        self.data.read().clone()
     }
 
 }
@@ -159,21 +350,24 @@ impl<T: Transport + Clone, N: Network, P: Provider<T, N>> DatabaseRef for JsonBl
     type Error = DbError;
 
     fn basic_ref(&self, address: Address) -> Result<Option<AccountInfo>, Self::Error> {        
-        match self.data.borrow().accounts.get(&address) {
-            Some(account) => return Ok(Some(account.clone())),
+        match self.data.read().accounts.get(&address) {
+            Some(account) => {
+                self.cache_hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(Some(account.clone()))
+            }
             None => {}
         }
         debug!("Fetching account {} from rpc", address);
-        let block_id = self.data.borrow().meta.header.number.into();
+        let block_id = self.data.read().meta.header.number.into();
+        let provider = self.provider().map_err(|err| DbError::GetAccount(address, err))?;
         let (balance, nonce, code) = self
-            .tokio_handle
-            .block_on(async {
-                let balance = self.provider.get_balance(address, block_id);
-                let nonce = self.provider.get_transaction_count(address, block_id);
-                let code = self.provider.get_code_at(address, block_id);
+            .fetch(async {
+                let balance = provider.get_balance(address, block_id);
+                let nonce = provider.get_transaction_count(address, block_id);
+                let code = provider.get_code_at(address, block_id);
                 tokio::try_join!(balance, nonce, code)
             })
-            .map_err(|err| DbError::GetAccount(address, anyhow::Error::new(err)))?;
+            .map_err(|err| DbError::GetAccount(address, err))?;
         let bytecode = Bytecode::new_raw(code);
         let account_info = AccountInfo::new(
             balance,
@@ -182,7 +376,7 @@ impl<T: Transport + Clone, N: Network, P: Provider<T, N>> DatabaseRef for JsonBl
             bytecode,
         );
         self.data
-            .borrow_mut()
+            .write()
             .accounts
             .insert(address, account_info.clone());
         Ok(Some(account_info))
@@ -191,27 +385,27 @@ impl<T: Transport + Clone, N: Network, P: Provider<T, N>> DatabaseRef for JsonBl
     fn storage_ref(&self, address: Address, index: U256) -> Result<U256, Self::Error> {        
         let value = self
             .data
-            .borrow()
+            .read()
             .storage
             .get(&address)
             .and_then(|s| s.get(&index).copied());
         if let Some(value) = value {
+            self.cache_hits.fetch_add(1, Ordering::Relaxed);
             return Ok(value);
         }
         debug!("Fetching storage {} {} from rpc", address, index);
-        let block_id = self.data.borrow().meta.header.number.into();
+        let block_id = self.data.read().meta.header.number.into();
+        let provider = self.provider().map_err(|err| DbError::GetStorage(address, index, err))?;
         let data = self
-            .tokio_handle
-            .block_on(async {
-                let storage = self
-                    .provider
+            .fetch(async {
+                let storage = provider
                     .get_storage_at(address, index, block_id)
                     .await;
                 storage
             })
-            .map_err(|err| DbError::GetStorage(address, index, anyhow::Error::new(err)))?;
+            .map_err(|err| DbError::GetStorage(address, index, err))?;
         self.data
-            .borrow_mut()
+            .write()
             .storage
             .entry(address)
             .or_default()
@@ -220,23 +414,29 @@ impl<T: Transport + Clone, N: Network, P: Provider<T, N>> DatabaseRef for JsonBl
     }
 
     fn block_hash_ref(&self, number: U256) -> Result<B256, Self::Error> {
-        let block_number = u64::try_from(number).unwrap();
-        match self.data.borrow().block_hashes.get(&block_number) {
-            Some(hash) => return Ok(*hash),
+        let fork_number = self.data.read().meta.header.number;
+        let Some(block_number) = block_hash_number(number, fork_number) else {
+            return Ok(B256::ZERO)
+        };
+        match self.data.read().block_hashes.get(&block_number) {
+            Some(hash) => {
+                self.cache_hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(*hash)
+            }
             None => {}
         }
         debug!("Fetching block hash {} from rpc", number);
+        let provider = self.provider().map_err(|err| DbError::GetBlockHash(block_number, err))?;
         let block = self
-            .tokio_handle
-            .block_on(async {
-                let block = self.provider.get_block(block_number.into(), false).await;
+            .fetch(async {
+                let block = provider.get_block(block_number.into(), false).await;
                 block
             })
-            .map_err(|err| DbError::GetBlockHash(block_number, anyhow::Error::new(err)))?;
+            .map_err(|err| DbError::GetBlockHash(block_number, err))?;
         let block = block.context("block not found")?;
         let hash = block.header.hash.context("block hash not found")?;
         self.data
-            .borrow_mut()
+            .write()
             .block_hashes
             .insert(block_number, hash);
         Ok(hash)
@@ -248,6 +448,16 @@ impl<T: Transport + Clone, N: Network, P: Provider<T, N>> DatabaseRef for JsonBl
 }
 
 
+/// The number of a block whose hash can be read from a fork of `fork_number`, [None] for a
+/// block whose hash reads as zero. Like `BLOCKHASH`, only the 256 blocks before the fork
+/// block have a hash, the fork block and the later ones have none.
+fn block_hash_number(number: U256, fork_number: u64) -> Option<u64> {
+    match u64::try_from(number) {
+        Ok(n) if n < fork_number && n.saturating_add(256) >= fork_number => Some(n),
+        _ => None,
+    }
+}
+
 pub struct ProxyDB<ExtDB> {
     pub hook_accounts: Map<Address, AccountInfo>,
     pub hook_storage: Map<Address, Map<U256, U256>>,
@@ -255,6 +465,12 @@ pub struct ProxyDB<ExtDB> {
     pub trace_basic: Vec<Address>,
     pub trace_storage: Vec<(Address, U256)>,
     pub trace_block_hashes: Vec<U256>,
+    /// State committed by the setup txs. The exploit reads it, but the traced state keeps
+    /// the values before the setup txs, as the guest replays them.
+    pub setup_accounts: Map<Address, AccountInfo>,
+    pub setup_storage: Map<Address, Map<U256, U256>>,
+    /// Accounts created or destroyed by the setup txs, their storage isn't read through
+    pub setup_cleared: BTreeSet<Address>,
 }
 
 impl<ExtDB> ProxyDB<ExtDB> {
@@ -262,6 +478,9 @@ impl<ExtDB> ProxyDB<ExtDB> {
         Self {
             hook_accounts: Map::default(),
             hook_storage: Map::default(),
+            setup_accounts: Map::default(),
+            setup_storage: Map::default(),
+            setup_cleared: BTreeSet::default(),
             db,
             trace_basic: Vec::default(),
             trace_storage: Vec::default(),
@@ -280,6 +499,26 @@ impl<ExtDB> ProxyDB<ExtDB> {
            .insert(index, value);
     }
 
+    /// The traced accounts and slots as an EIP-2930 access list
+    pub fn as_access_list(&self) -> AccessList {
+        let mut touched: Map<Address, BTreeSet<U256>> = Map::new();
+        for address in self.trace_basic.iter() {
+            touched.entry(*address).or_default();
+        }
+        for (address, index) in self.trace_storage.iter() {
+            touched.entry(*address).or_default().insert(*index);
+        }
+        AccessList(
+            touched
+                .into_iter()
+                .map(|(address, slots)| AccessListItem {
+                    address,
+                    storage_keys: slots.into_iter().map(B256::from).collect(),
+                })
+                .collect(),
+        )
+    }
+
 }
 
 
@@ -288,7 +527,11 @@ impl<ExtDB: DatabaseRef> Database for ProxyDB<ExtDB> {
 
     fn basic(&mut self, address:Address) -> Result<Option<AccountInfo> ,Self::Error> {
         self.trace_basic.push(address);
-        <Self as DatabaseRef>::basic_ref(self, address)
+        let info = <Self as DatabaseRef>::basic_ref(self, address)?;
+        match self.setup_accounts.get(&address) {
+            Some(info) => Ok(Some(info.clone())),
+            None => Ok(info),
+        }
     }
 
     #[inline]
@@ -299,7 +542,12 @@ impl<ExtDB: DatabaseRef> Database for ProxyDB<ExtDB> {
 
     fn storage(&mut self, address:Address, index:U256) -> Result<U256,Self::Error> {
         self.trace_storage.push((address, index));
-        <Self as DatabaseRef>::storage_ref(self, address, index)
+        let value = <Self as DatabaseRef>::storage_ref(self, address, index)?;
+        match self.setup_storage.get(&address).and_then(|s| s.get(&index)) {
+            Some(value) => Ok(*value),
+            None if self.setup_cleared.contains(&address) => Ok(U256::ZERO),
+            None => Ok(value),
+        }
     }
 
     fn block_hash(&mut self, number:U256) -> Result<B256,Self::Error> {
@@ -309,6 +557,26 @@ impl<ExtDB: DatabaseRef> Database for ProxyDB<ExtDB> {
 
 }
 
+impl<ExtDB> DatabaseCommit for ProxyDB<ExtDB> {
+    fn commit(&mut self, changes: HashMap<Address, Account>) {
+        for (address, account) in changes {
+            if !account.is_touched() {
+                continue;
+            }
+            if account.is_selfdestructed() || account.is_created() {
+                self.setup_cleared.insert(address);
+                self.setup_storage.remove(&address);
+            }
+            let info = if account.is_selfdestructed() { AccountInfo::default() } else { account.info };
+            self.setup_accounts.insert(address, info);
+            let storage = self.setup_storage.entry(address).or_default();
+            for (index, slot) in account.storage {
+                storage.insert(index, slot.present_value());
+            }
+        }
+    }
+}
+
 impl <ExtDB: DatabaseRef> DatabaseRef for ProxyDB<ExtDB> {
     type Error = ExtDB::Error;
     
@@ -348,12 +616,13 @@ impl <ExtDB: DatabaseRef> DatabaseRef for ProxyDB<ExtDB> {
 impl <ExtDB: DatabaseRef> ProxyDB<ExtDB> 
 where <ExtDB as DatabaseRef>::Error: std::fmt::Debug
 {
-    pub fn into_memdb(&self) -> MemDB {
+    pub fn into_memdb(&self) -> Result<MemDB> {
         let mut accounts: Map<Address, AccountStorage> = Map::new();
         let mut block_hashes: Vec<(u64, B256)> = vec![];
 
         for (address, slot) in self.trace_storage.iter() {
-            let slot_value = self.storage_ref(address.clone(), slot.clone()).unwrap();
+            let slot_value = self.storage_ref(address.clone(), slot.clone())
+                .map_err(|err| anyhow!("Failed to read storage {} of {}: {:?}", slot, address, err))?;
 
             match accounts.get_mut(address) {
                 Some(account) => {
@@ -361,13 +630,13 @@ where <ExtDB as DatabaseRef>::Error: std::fmt::Debug
                     account.storage.insert(*slot, slot_value);
                 }
                 None => {
-                    let info = self.basic_ref(address.clone()).unwrap().unwrap();
-                    let account = AccountStorage {
+                    let info = self.traced_account(*address)?;
+                    let mut account = AccountStorage {
                         info: info,
                         storage: Map::new(),
                     };
+                    account.storage.insert(*slot, slot_value);
                     accounts.insert(address.clone(), account);
-                    accounts.get_mut(address).unwrap().storage.insert(*slot, slot_value);
                 },
                 
             }
@@ -376,7 +645,7 @@ where <ExtDB as DatabaseRef>::Error: std::fmt::Debug
             match accounts.get(address) {
                 Some(_) => {},
                 None => {
-                    let info = self.basic_ref(address.clone()).unwrap().unwrap();
+                    let info = self.traced_account(*address)?;
                     let account = AccountStorage {
                         info: info,
                         storage: Map::new(),
@@ -387,9 +656,202 @@ where <ExtDB as DatabaseRef>::Error: std::fmt::Debug
         }
 
         for block_number in self.trace_block_hashes.iter() {
-            let block_hash = self.block_hash_ref(block_number.clone()).unwrap();
-            block_hashes.push((block_number.clone().try_into().unwrap(), block_hash));
+            let block_hash = self.block_hash_ref(block_number.clone())
+                .map_err(|err| anyhow!("Failed to read the hash of block {}: {:?}", block_number, err))?;
+            let block_number = u64::try_from(*block_number)
+                .map_err(|_| anyhow!("The block number {} read by the exploit is out of range", block_number))?;
+            block_hashes.push((block_number, block_hash));
+        }
+        // the trace is in the order of the reads, the input must not depend on it
+        block_hashes.sort();
+        block_hashes.dedup();
+        Ok(MemDB { accounts, block_hashes})
+    }
+
+    fn traced_account(&self, address: Address) -> Result<AccountInfo> {
+        self.basic_ref(address)
+            .map_err(|err| anyhow!("Failed to read account {}: {:?}", address, err))?
+            .with_context(|| format!("The traced account {} doesn't exist", address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy_provider::ProviderBuilder;
+
+    fn state() -> MemDB {
+        let mut db = MemDB::default();
+        for i in 1..4u8 {
+            let mut account = AccountStorage::default();
+            account.info.balance = U256::from(i);
+            account.storage.insert(U256::from(i), U256::from(i * 10));
+            account.storage.insert(U256::from(i + 1), U256::from(i * 20));
+            db.accounts.insert(Address::repeat_byte(i), account);
         }
-        MemDB { accounts, block_hashes}
+        db.block_hashes = vec![(7, B256::repeat_byte(7)), (8, B256::repeat_byte(8))];
+        db
     }
-}
\ No newline at end of file
+
+    fn header(number: u64) -> BlockHeader {
+        BlockHeader {
+            parent_hash: B256::ZERO,
+            uncles_hash: B256::ZERO,
+            author: Address::ZERO,
+            state_root: B256::ZERO,
+            transactions_root: B256::ZERO,
+            receipts_root: B256::ZERO,
+            logs_bloom: Default::default(),
+            difficulty: U256::ZERO,
+            number,
+            gas_limit: 30_000_000,
+            gas_used: 0,
+            timestamp: 1_705_000_000,
+            extra_data: Default::default(),
+            mix_hash: B256::ZERO,
+            nonce: Default::default(),
+            base_fee_per_gas: U256::ZERO,
+            withdrawals_root: None,
+            blob_gas_used: None,
+            excess_blob_gas: None,
+            parent_beacon_block_root: None,
+        }
+    }
+
+    /// Reads the state of [state] through a [ProxyDB] in the given order
+    fn traced(reads: &[(u8, Option<u8>)], block_hashes: &[u64]) -> MemDB {
+        let state = state();
+        let mut db = ProxyDB::new(&state);
+        for (account, slot) in reads {
+            let address = Address::repeat_byte(*account);
+            match slot {
+                Some(slot) => db.storage(address, U256::from(*slot)).unwrap(),
+                None => db.basic(address).unwrap().unwrap().balance,
+            };
+        }
+        for number in block_hashes {
+            db.block_hash(U256::from(*number)).unwrap();
+        }
+        db.into_memdb().unwrap()
+    }
+
+    #[test]
+    fn traced_state_does_not_depend_on_the_read_order() {
+        let first = traced(
+            &[(1, None), (1, Some(1)), (2, Some(3)), (3, None), (2, Some(2)), (1, Some(1))],
+            &[7, 8, 7],
+        );
+        let shuffled = traced(
+            &[(3, None), (2, Some(2)), (1, Some(1)), (2, Some(3)), (1, None)],
+            &[8, 7],
+        );
+        assert_eq!(first, shuffled);
+        assert_eq!(serde_json::to_vec(&first).unwrap(), serde_json::to_vec(&shuffled).unwrap());
+    }
+
+    #[test]
+    fn built_meta_equals_the_hand_built_one() {
+        let meta = BlockchainDbMeta::builder().chain_id(11155111).header(header(100)).build();
+        assert_eq!(meta, BlockchainDbMeta { chain_spec: ChainSpec::new(11155111), header: header(100) });
+        assert_eq!(meta, BlockchainDbMeta::new(ChainSpec::new(11155111), header(100)));
+        assert_ne!(meta, BlockchainDbMeta::builder().chain_id(11155111).header(header(101)).build());
+        assert_ne!(meta, BlockchainDbMeta::builder().header(header(100)).build());
+
+        let meta = BlockchainDbMeta::builder().build();
+        assert_eq!(meta, BlockchainDbMeta::default());
+        assert_eq!(meta.chain_spec, ChainSpec::mainnet());
+        assert_eq!(meta.header, BlockHeader::default());
+    }
+
+    #[test]
+    fn snapshot_keeps_the_state_of_the_input_and_loads_back() {
+        // the rpc cache also holds state the input doesn't read
+        let mut cache = JsonBlockCacheData {
+            meta: BlockchainDbMeta::new(ChainSpec::mainnet(), header(100)),
+            accounts: Map::new(),
+            storage: Map::new(),
+            block_hashes: Map::new(),
+        };
+        for i in 1..6u8 {
+            let address = Address::repeat_byte(i);
+            cache.accounts.insert(address, AccountInfo { balance: U256::from(i), ..Default::default() });
+            for slot in 1..6u8 {
+                cache.storage.entry(address).or_default().insert(U256::from(slot), U256::from(slot));
+            }
+        }
+        for number in 7..10u64 {
+            cache.block_hashes.insert(number, B256::repeat_byte(number as u8));
+        }
+
+        let snapshot = cache.snapshot(&state());
+        assert_eq!(snapshot.meta, cache.meta);
+        assert_eq!(snapshot.accounts.keys().copied().collect::<Vec<_>>(), (1..4u8).map(Address::repeat_byte).collect::<Vec<_>>());
+        assert_eq!(snapshot.storage[&Address::repeat_byte(2)].keys().copied().collect::<Vec<_>>(), vec![U256::from(2), U256::from(3)]);
+        assert_eq!(snapshot.block_hashes.keys().copied().collect::<Vec<_>>(), vec![7, 8]);
+
+        let loaded: JsonBlockCacheData = serde_json::from_str(&serde_json::to_string(&snapshot).unwrap()).unwrap();
+        assert_eq!(serde_json::to_value(&loaded).unwrap(), serde_json::to_value(&snapshot).unwrap());
+    }
+
+    #[test]
+    fn concurrent_readers_share_the_cache() {
+        let meta = BlockchainDbMeta::new(ChainSpec::mainnet(), header(100));
+        let mut cache = JsonBlockCacheData {
+            meta: meta.clone(),
+            accounts: Map::new(),
+            storage: Map::new(),
+            block_hashes: Map::new(),
+        };
+        for i in 1..4u8 {
+            cache.accounts.insert(Address::repeat_byte(i), AccountInfo::from_balance(U256::from(i)));
+            cache.storage.entry(Address::repeat_byte(i)).or_default().insert(U256::from(i), U256::from(i * 10));
+        }
+        let path = std::env::temp_dir().join(format!("zkprover-{}-concurrent-cache.json", std::process::id()));
+        fs::write(&path, serde_json::to_string(&cache).unwrap()).unwrap();
+        // nothing listens on the rpc, a read the cache misses fails
+        let provider = ProviderBuilder::new().on_http("http://127.0.0.1:1".try_into().unwrap()).unwrap();
+        let db = JsonBlockCacheDB::new(provider, meta, Some(path.clone()));
+
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for i in 1..4u8 {
+                        assert_eq!(db.basic_ref(Address::repeat_byte(i)).unwrap().unwrap().balance, U256::from(i));
+                        assert_eq!(db.storage_ref(Address::repeat_byte(i), U256::from(i)).unwrap(), U256::from(i * 10));
+                    }
+                    assert!(db.basic_ref(Address::repeat_byte(9)).is_err());
+                });
+            }
+        });
+        assert_eq!(db.read_counts(), (8 * 6, 8));
+        assert_eq!(db.data().accounts.len(), 3);
+        drop(db);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn block_hashes_beyond_the_window_read_as_zero() {
+        assert_eq!(block_hash_number(U256::from(1000), 1000), None);
+        assert_eq!(block_hash_number(U256::from(999), 1000), Some(999));
+        assert_eq!(block_hash_number(U256::from(744), 1000), Some(744));
+        assert_eq!(block_hash_number(U256::from(743), 1000), None);
+        assert_eq!(block_hash_number(U256::from(0), 100), Some(0));
+        assert_eq!(block_hash_number(U256::from(u64::MAX), 1000), None);
+        assert_eq!(block_hash_number(U256::MAX, 1000), None);
+    }
+
+    #[test]
+    fn blocks_run_the_hardfork_of_their_chain() {
+        let mainnet = ChainSpec::mainnet();
+        assert_eq!(mainnet.spec_id(19_426_587, 1_710_338_135).unwrap(), SpecId::CANCUN);
+        assert_eq!(mainnet.spec_id(19_426_586, 1_710_338_123).unwrap(), SpecId::SHANGHAI);
+        assert_eq!(mainnet.spec_id(15_537_394, 1_663_224_179).unwrap(), SpecId::MERGE);
+        assert_eq!(mainnet.spec_id(15_537_393, 1_663_224_162).unwrap(), SpecId::LONDON);
+        assert!(mainnet.spec_id(12_964_999, 1_628_166_812).is_err());
+
+        // sepolia forked to Cancun a month before mainnet
+        assert_eq!(ChainSpec::new(11155111).spec_id(5_187_023, 1_706_655_072).unwrap(), SpecId::CANCUN);
+        // an unknown chain follows the mainnet rules of the time
+        assert_eq!(ChainSpec::new(10).spec_id(100, 1_700_000_000).unwrap(), SpecId::SHANGHAI);
+    }
+}
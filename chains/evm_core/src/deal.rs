@@ -1,16 +1,40 @@
-use std::str::FromStr;
-use alloy_primitives::{Address, U256};
+use std::{collections::BTreeMap as Map, fmt::Debug, fs, path::Path, str::FromStr};
+use alloy_primitives::{address, Address, U256};
+use alloy_sol_types::SolCall;
+use revm::{primitives::{ExecutionResult, TransactTo}, Database, DatabaseRef, Evm};
 use serde::{Serialize, Deserialize};
-use anyhow::Result;
+use serde_json::value::RawValue;
+use anyhow::{bail, Context, Result};
+use bridge::DEFAULT_CONTRACT_ADDRESS;
 
-use crate::utils::parse_ether_value;
+use crate::db::ProxyDB;
+use crate::helper_contract::IERC20;
+use crate::utils::{explain_evm_error, parse_ether_value};
 
 #[derive(Clone, Debug, Eq, PartialEq, Hash, Deserialize, Serialize)]
 pub struct DealRecord {
+    /// The account receiving the deal, the poc contract by default
+    pub address: Address,
     pub token: Address,
     pub balance: U256,
 }
 
+impl DealRecord {
+    /// The zero token stands for ether, dealt by setting the account balance rather than a
+    /// storage slot of a token contract
+    pub fn is_ether(&self) -> bool {
+        self.token == Address::ZERO
+    }
+}
+
+/// The ether balance the deals give the account, the last deal wins.
+pub fn ether_balance(deals: &[DealRecord], address: Address) -> Option<U256> {
+    deals.iter()
+        .filter(|deal| deal.is_ether() && deal.address == address)
+        .last()
+        .map(|deal| deal.balance)
+}
+
 #[derive(Debug, Clone, PartialEq, thiserror::Error)]
 #[error("{0}")]
 pub struct ParseDealError(String);
@@ -22,17 +46,245 @@ impl FromStr for DealRecord {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let err = || {
             ParseDealError(
-                "deal record format must be `<token>:<balance>` or `<balance>`"
+                "deal record format must be `<token>:<balance>` or `<balance>`, optionally followed by `:for:<address>`"
                     .to_string(),
             )
         };
+        let (s, address) = match s.split_once(":for:") {
+            Some((deal, address)) => {
+                let address = Address::from_str(address.trim())
+                    .map_err(|_x| ParseDealError("error `<address>`".to_string()))?;
+                (deal, address)
+            }
+            None => (s, DEFAULT_CONTRACT_ADDRESS),
+        };
+        if address == Address::ZERO {
+            return Err(ParseDealError("a deal can not target the zero address".to_string()));
+        }
         let mut iter = s.rsplit(':');
         let balance = iter.next().ok_or_else(err)?.trim().to_string();
         let balance = parse_ether_value(&balance).map_err(|_x| ParseDealError("error `<balance>`".to_string()))?;
-        let token = iter.next().map(|x| Address::from_str(x).unwrap()).unwrap_or(Address::default());
+        let token = match iter.next() {
+            Some(token) => Address::from_str(token.trim()).map_err(|_x| ParseDealError("error `<token>`".to_string()))?,
+            None => Address::default(),
+        };
+        if iter.next().is_some() {
+            return Err(err());
+        }
         Ok(DealRecord {
+            address,
             token,
             balance,
         })
     }
-}
\ No newline at end of file
+}
+
+/// A deal entry of a deal file, the balance accepts the same units as `--deal`.
+#[derive(Debug, Deserialize)]
+struct DealEntry {
+    #[serde(default = "default_deal_address")]
+    address: Address,
+    #[serde(default)]
+    token: Address,
+    balance: String,
+}
+
+fn default_deal_address() -> Address {
+    DEFAULT_CONTRACT_ADDRESS
+}
+
+#[derive(Debug, Deserialize)]
+struct TomlDealFile {
+    deals: Vec<toml::Spanned<DealEntry>>,
+}
+
+/// The line of `content` at the byte offset, from 1
+fn line_at(content: &str, offset: usize) -> usize {
+    content[..offset].matches('\n').count() + 1
+}
+
+/// Loads deal records from a json or toml file.
+///
+/// A json file holds an array of `{"balance": "10gwei"}`, a toml file holds `[[deals]]`
+/// tables with the same keys. An optional `address` key sets the receiver, the poc contract
+/// by default, and a `token` key deals that erc20 instead of ether. An error names the
+/// deal and its line.
+pub fn load_deals(path: impl AsRef<Path>) -> Result<Vec<DealRecord>> {
+    let path = path.as_ref();
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read deal file {:?}", path))?;
+    let entries: Vec<(usize, DealEntry)> = match path.extension().and_then(|x| x.to_str()) {
+        Some("json") => {
+            let entries: Vec<&RawValue> = serde_json::from_str(&content)
+                .with_context(|| format!("Malformed deal file {:?}", path))?;
+            entries
+                .into_iter()
+                .enumerate()
+                .map(|(i, entry)| {
+                    // the raw entry borrows from the content
+                    let line = line_at(&content, entry.get().as_ptr() as usize - content.as_ptr() as usize);
+                    let entry = serde_json::from_str(entry.get())
+                        .with_context(|| format!("Malformed deal #{} at {:?} line {}", i, path, line))?;
+                    Ok((line, entry))
+                })
+                .collect::<Result<_>>()?
+        }
+        Some("toml") => toml::from_str::<TomlDealFile>(&content)
+            .with_context(|| format!("Malformed deal file {:?}", path))?
+            .deals
+            .into_iter()
+            .map(|entry| (line_at(&content, entry.span().start), entry.into_inner()))
+            .collect(),
+        _ => bail!("Deal file {:?} must be a .json or .toml file", path),
+    };
+    entries
+        .into_iter()
+        .enumerate()
+        .map(|(i, (line, entry))| {
+            let balance = parse_ether_value(entry.balance.trim()).with_context(|| {
+                format!("Malformed balance `{}` of deal #{} at {:?} line {}", entry.balance, i, path, line)
+            })?;
+            if entry.address == Address::ZERO {
+                bail!("Deal #{} at {:?} line {} targets the zero address", i, path, line)
+            }
+            Ok(DealRecord { address: entry.address, token: entry.token, balance })
+        })
+        .collect()
+}
+
+/// The storage the token deals write, by token. Every receiver gets its balance in the slot
+/// the token keeps it in, the total supply is left as is. A later deal of the same account
+/// and token wins.
+pub fn token_deal_storage<DB: DatabaseRef>(db: &DB, deals: &[DealRecord]) -> Result<Map<Address, Map<U256, U256>>>
+where
+    DB::Error: Debug,
+{
+    let mut storage: Map<Address, Map<U256, U256>> = Map::new();
+    for deal in deals.iter().filter(|deal| !deal.is_ether()) {
+        let slot = balance_slot(db, deal.token, deal.address)?;
+        storage.entry(deal.token).or_default().insert(slot, deal.balance);
+    }
+    Ok(storage)
+}
+
+/// The slot of `token` holding the balance of `account`, found like forge-std's `deal`: of
+/// the slots `balanceOf` reads, the last one whose value it returns once changed.
+fn balance_slot<DB: DatabaseRef>(db: &DB, token: Address, account: Address) -> Result<U256>
+where
+    DB::Error: Debug,
+{
+    let mut probe = ProxyDB::new(db);
+    let balance = balance_of(&mut probe, token, account)
+        .with_context(|| format!("Failed to read the balance of {} in the token {}", account, token))?;
+    let marker = balance.wrapping_add(U256::from(1));
+    for (_, index) in probe.trace_storage.iter().rev().filter(|(address, _)| *address == token) {
+        let mut patched = ProxyDB::new(db);
+        patched.insert_account_storage(token, *index, marker);
+        if balance_of(&mut patched, token, account).ok() == Some(marker) {
+            return Ok(*index)
+        }
+    }
+    bail!("Can not find the balance slot of {} in the token {}, only plain erc20 balances can be dealt", account, token)
+}
+
+/// The `balanceOf(account)` of the token
+fn balance_of<DB: Database>(db: DB, token: Address, account: Address) -> Result<U256>
+where
+    DB::Error: Debug,
+{
+    let mut evm = Evm::builder()
+        .with_db(db)
+        .modify_tx_env(|tx| {
+            tx.caller = address!("1000000000000000000000000000000000000000");
+            tx.transact_to = TransactTo::Call(token);
+            tx.data = IERC20::balanceOfCall { account }.abi_encode().into();
+        })
+        .build();
+    let result = match evm.transact_preverified() {
+        Ok(result_and_state) => result_and_state.result,
+        Err(err) => bail!("{}", explain_evm_error(&err)),
+    };
+    match result {
+        ExecutionResult::Success { output, .. } => Ok(IERC20::balanceOfCall::abi_decode_returns(output.data(), false)?._0),
+        result => bail!("balanceOf did not succeed: {:?}", result),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy_primitives::keccak256;
+    use revm::{db::{CacheDB, EmptyDB}, primitives::{hex, AccountInfo, Bytecode}};
+
+    fn deal_file(name: &str, content: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("zkprover-{}-{}", std::process::id(), name));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn ether_deals_parse() {
+        let deal = DealRecord::from_str("1ether").unwrap();
+        assert!(deal.is_ether());
+        assert_eq!(deal.address, DEFAULT_CONTRACT_ADDRESS);
+        assert_eq!(deal.balance, U256::from(10).pow(U256::from(18)));
+
+        let receiver = Address::repeat_byte(1);
+        let deal = DealRecord::from_str(&format!("10gwei:for:{}", receiver)).unwrap();
+        assert_eq!(deal.address, receiver);
+    }
+
+    #[test]
+    fn token_deals_load_with_their_line() {
+        let usdt: Address = "0xdac17f958d2ee523a2206206994597c13d831ec7".parse().unwrap();
+        let deal = DealRecord::from_str("0xdac17f958d2ee523a2206206994597c13d831ec7:10gwei").unwrap();
+        assert_eq!(deal, DealRecord { address: DEFAULT_CONTRACT_ADDRESS, token: usdt, balance: U256::from(10_000_000_000u64) });
+
+        let path = deal_file("deals.json", "[\n  {\"balance\": \"1ether\"},\n  {\"token\": \"0xdac17f958d2ee523a2206206994597c13d831ec7\", \"balance\": \"10\"}\n]");
+        let deals = load_deals(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(deals[1], DealRecord { address: DEFAULT_CONTRACT_ADDRESS, token: usdt, balance: U256::from(10) });
+
+        let path = deal_file("deals.toml", "[[deals]]\nbalance = \"1ether\"\n\n[[deals]]\ntoken = \"0xdac17f958d2ee523a2206206994597c13d831ec7\"\nbalance = \"lots\"\n");
+        let err = load_deals(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(err.to_string(), format!("Malformed balance `lots` of deal #1 at {:?} line 4", path));
+
+        let path = deal_file("bad-deals.json", "[\n  {\"balance\": \"1ether\"},\n  {\"token\": \"usdt\", \"balance\": \"10\"}\n]");
+        let err = load_deals(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(err.to_string(), format!("Malformed deal #1 at {:?} line 3", path));
+    }
+
+    #[test]
+    fn token_deals_write_the_balance_slot() {
+        let token = Address::repeat_byte(0x70);
+        // reads a flag at slot 5, then returns the balance of the account in the mapping at
+        // slot 0: mstore(0, account) mstore(32, 0) return(sload(keccak256(0, 64)))
+        let code = hex::decode("60055450600435600052600060205260406000205460005260206000f3").unwrap();
+        let code = Bytecode::new_raw(code.into());
+        let mut db = CacheDB::new(EmptyDB::default());
+        db.insert_account_info(token, AccountInfo::new(U256::ZERO, 1, code.hash_slow(), code));
+        let slot = |account: Address| {
+            let mut key = account.into_word().to_vec();
+            key.extend_from_slice(&[0; 32]);
+            U256::from_be_bytes(keccak256(key).0)
+        };
+
+        let receiver = Address::repeat_byte(1);
+        let deals = [
+            DealRecord { address: DEFAULT_CONTRACT_ADDRESS, token: Address::ZERO, balance: U256::from(1) },
+            DealRecord { address: DEFAULT_CONTRACT_ADDRESS, token, balance: U256::from(1000) },
+            DealRecord { address: receiver, token, balance: U256::from(5) },
+        ];
+        let storage = token_deal_storage(&db, &deals).unwrap();
+        assert_eq!(storage, Map::from([(token, Map::from([
+            (slot(DEFAULT_CONTRACT_ADDRESS), U256::from(1000)),
+            (slot(receiver), U256::from(5)),
+        ]))]));
+
+        // an account without code has no balanceOf
+        let err = token_deal_storage(&db, &[DealRecord { address: receiver, token: Address::repeat_byte(0x71), balance: U256::from(5) }]);
+        assert!(err.is_err());
+    }
+}
@@ -7,5 +7,12 @@ sol! {
         function batchDeal(address[] calldata accounts, address[] calldata tokens, uint256[] calldata balances) external;
         function balances(address[] calldata users, address[] calldata tokens) external view returns (uint256[] memory);
     }
+
+    interface IERC20 {
+        event Transfer(address indexed from, address indexed to, uint256 value);
+        function balanceOf(address account) external view returns (uint256);
+        function decimals() external view returns (uint8);
+        function symbol() external view returns (string memory);
+    }
 }
 
@@ -0,0 +1,251 @@
+use revm::{
+    interpreter::{gas::CALL_STIPEND, opcode, CallInputs, CallOutcome, Interpreter, InterpreterAction},
+    primitives::{Address, Bytes, U256},
+    Database, EvmContext, Inspector,
+};
+use alloy_json_abi::JsonAbi;
+use bridge::DEFAULT_CONTRACT_ADDRESS;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{decode_call, decode_revert};
+
+/// An inspector that watches the exploit during preflight and records the behaviours
+/// worth reporting to the user.
+#[derive(Debug, Clone, Default)]
+pub struct PreflightInspector {
+    /// The exploit executed the `ORIGIN` opcode
+    pub reads_origin: bool,
+    /// The gas spent by opcode class, only accumulated when [Some]
+    pub gas_breakdown: Option<GasBreakdown>,
+    /// The blocks within the `BLOCKHASH` window whose hash the exploit read as zero, a
+    /// missing hash rather than the zero of a block out of the window
+    pub zero_block_hashes: Vec<u64>,
+    /// The opcode being executed and the gas remaining before it
+    step: Option<(u8, u64)>,
+    /// The block number a `BLOCKHASH` being executed asks for
+    blockhash_number: Option<U256>,
+}
+
+/// The gas the opcodes of the exploit spent, by class. The intrinsic gas of the tx and the
+/// refunds are not included.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GasBreakdown {
+    /// `SLOAD`, `SSTORE`, `TLOAD` and `TSTORE`
+    pub storage: u64,
+    /// The calls and creates themselves, the gas forwarded to them is counted in their frames
+    pub calls: u64,
+    /// Every other opcode
+    pub computation: u64,
+}
+
+impl PreflightInspector {
+    pub fn new(gas_breakdown: bool) -> Self {
+        Self { gas_breakdown: gas_breakdown.then(GasBreakdown::default), ..Default::default() }
+    }
+}
+
+impl GasBreakdown {
+    fn add(&mut self, op: u8, gas: u64) {
+        match op {
+            opcode::SLOAD | opcode::SSTORE | opcode::TLOAD | opcode::TSTORE => self.storage += gas,
+            opcode::CALL | opcode::CALLCODE | opcode::DELEGATECALL | opcode::STATICCALL
+            | opcode::CREATE | opcode::CREATE2 | opcode::SELFDESTRUCT => self.calls += gas,
+            _ => self.computation += gas,
+        }
+    }
+}
+
+impl<DB: Database> Inspector<DB> for PreflightInspector {
+    fn step(&mut self, interp: &mut Interpreter, _context: &mut EvmContext<DB>) {
+        let op = interp.current_opcode();
+        if op == opcode::ORIGIN {
+            self.reads_origin = true;
+        }
+        if op == opcode::BLOCKHASH {
+            self.blockhash_number = interp.stack.peek(0).ok();
+        }
+        if self.gas_breakdown.is_some() {
+            self.step = Some((op, interp.gas.remaining()));
+        }
+    }
+
+    fn step_end(&mut self, interp: &mut Interpreter, context: &mut EvmContext<DB>) {
+        if let Some(number) = self.blockhash_number.take() {
+            let current = context.env.block.number;
+            let in_window = number < current && current - number <= U256::from(256);
+            if in_window && interp.stack.peek(0) == Ok(U256::ZERO) {
+                self.zero_block_hashes.push(number.saturating_to());
+            }
+        }
+        let (Some(breakdown), Some((op, remaining))) = (self.gas_breakdown.as_mut(), self.step.take()) else {
+            return;
+        };
+        // the gas a call or create forwards is already taken from the frame here
+        let forwarded = match &interp.next_action {
+            InterpreterAction::Call { inputs } => {
+                let stipend = matches!(op, opcode::CALL | opcode::CALLCODE) && inputs.transfer.value != U256::ZERO;
+                inputs.gas_limit - if stipend { CALL_STIPEND } else { 0 }
+            }
+            InterpreterAction::Create { inputs } => inputs.gas_limit,
+            _ => 0,
+        };
+        breakdown.add(op, remaining.saturating_sub(interp.gas.remaining()).saturating_sub(forwarded));
+    }
+}
+
+/// A call made while running the exploit.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CallTrace {
+    /// 0 is the exploit tx itself
+    pub depth: usize,
+    pub caller: Address,
+    pub target: Address,
+    pub value: U256,
+    pub input: Bytes,
+    /// [None] until the call returns
+    pub success: Option<bool>,
+    /// Including the calls it made, zero until the call returns
+    #[serde(default)]
+    pub gas_used: u64,
+    /// The return data, or the revert data
+    #[serde(default)]
+    pub output: Bytes,
+}
+
+/// An inspector that records every call in the order they are made.
+#[derive(Debug, Clone, Default)]
+pub struct CallTracer {
+    pub calls: Vec<CallTrace>,
+    /// The calls that haven't returned yet
+    pending: Vec<usize>,
+}
+
+impl<DB: Database> Inspector<DB> for CallTracer {
+    fn call(&mut self, context: &mut EvmContext<DB>, inputs: &mut CallInputs) -> Option<CallOutcome> {
+        self.pending.push(self.calls.len());
+        self.calls.push(CallTrace {
+            depth: context.journaled_state.depth() as usize,
+            caller: inputs.context.caller,
+            target: inputs.contract,
+            value: inputs.transfer.value,
+            input: inputs.input.clone(),
+            success: None,
+            ..Default::default()
+        });
+        None
+    }
+
+    fn call_end(&mut self, _context: &mut EvmContext<DB>, _inputs: &CallInputs, outcome: CallOutcome) -> CallOutcome {
+        if let Some(index) = self.pending.pop() {
+            let call = &mut self.calls[index];
+            call.success = Some(outcome.instruction_result().is_ok());
+            call.gas_used = outcome.result.gas.spent();
+            call.output = outcome.result.output.clone();
+        }
+        outcome
+    }
+}
+
+/// Renders the calls as a tree in the style of the Foundry traces,
+/// `[gas] target::function(args)` with the return data or revert closing each call. The
+/// calls to the poc contract are named with its abi.
+pub fn format_call_tree(calls: &[CallTrace], poc_abi: Option<&JsonAbi>) -> String {
+    let mut lines = Vec::new();
+    let mut index = 0;
+    while index < calls.len() {
+        index = render_call(calls, index, "", "", poc_abi, &mut lines);
+    }
+    lines.join("\n")
+}
+
+/// Renders the call at `index` and its subcalls, returns the index of the call after them.
+fn render_call(
+    calls: &[CallTrace],
+    index: usize,
+    head: &str,
+    indent: &str,
+    poc_abi: Option<&JsonAbi>,
+    lines: &mut Vec<String>,
+) -> usize {
+    let call = &calls[index];
+    let abi = if call.target == DEFAULT_CONTRACT_ADDRESS { poc_abi } else { None };
+    lines.push(format!("{}[{}] {}::{}", head, call.gas_used, call.target, decode_call(&call.input, abi)));
+    let mut next = index + 1;
+    while next < calls.len() && calls[next].depth > call.depth {
+        next = render_call(calls, next, &format!("{}├─ ", indent), &format!("{}│   ", indent), poc_abi, lines);
+    }
+    let result = match call.success {
+        Some(true) => format!("0x{}", hex::encode(&call.output)),
+        Some(false) => format!("[Revert] {}", decode_revert(&call.output, abi)),
+        None => "[Unfinished]".to_string(),
+    };
+    lines.push(format!("{}└─ ← {}", indent, result));
+    next
+}
+
+/// One line per call, indented by depth, with the selector and how the call ended.
+pub fn format_call_trace(calls: &[CallTrace]) -> String {
+    let mut lines = Vec::new();
+    for call in calls.iter() {
+        let selector = call.input.get(..4).map(hex::encode).unwrap_or_default();
+        let status = match call.success {
+            Some(true) => "ok",
+            Some(false) => "revert",
+            None => "unfinished",
+        };
+        lines.push(format!(
+            "{}{} -> {} value: {} selector: 0x{} {}",
+            "  ".repeat(call.depth), call.caller, call.target, call.value, selector, status
+        ));
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use revm::{
+        db::{CacheDB, EmptyDB},
+        inspector_handle_register,
+        primitives::{hex, AccountInfo, Bytecode, TransactTo},
+        Evm,
+    };
+
+    #[test]
+    fn storage_heavy_poc_spends_its_gas_on_storage() {
+        let target = Address::repeat_byte(0x42);
+        // PUSH1 1 PUSH1 i SSTORE for the fresh slots 0 to 3, then
+        // CALL(0x1000, target, 1 wei, 0, 0, 0, 0) and STOP
+        let mut code = hex::decode("600160005560016001556001600255600160035560006000600060006001").unwrap();
+        code.push(0x73);
+        code.extend_from_slice(target.as_slice());
+        code.extend_from_slice(&hex::decode("611000f100").unwrap());
+
+        let mut db = CacheDB::new(EmptyDB::default());
+        db.insert_account_info(Address::repeat_byte(1), AccountInfo::from_balance(U256::from(1)));
+        let code = Bytecode::new_raw(code.into());
+        db.insert_account_info(DEFAULT_CONTRACT_ADDRESS, AccountInfo::new(U256::from(1), 1, code.hash_slow(), code));
+        db.insert_account_info(target, AccountInfo { nonce: 1, ..Default::default() });
+        let mut evm = Evm::builder()
+            .with_db(db)
+            .with_external_context(PreflightInspector::new(true))
+            .modify_tx_env(|tx| {
+                tx.caller = Address::repeat_byte(1);
+                tx.transact_to = TransactTo::Call(DEFAULT_CONTRACT_ADDRESS);
+                tx.gas_limit = 1_000_000;
+            })
+            .append_handler_register(inspector_handle_register)
+            .build();
+        assert!(evm.transact().unwrap().result.is_success());
+
+        let breakdown = evm.context.external.gas_breakdown.unwrap();
+        // 4 cold SSTOREs of a fresh slot
+        assert_eq!(breakdown.storage, 4 * 22_100);
+        // a cold account and the value transfer, the forwarded gas with its stipend is not
+        // spent by the CALL itself
+        assert_eq!(breakdown.calls, 2_600 + 9_000);
+        // 15 PUSHes
+        assert_eq!(breakdown.computation, 15 * 3);
+        assert!(breakdown.storage > 2 * (breakdown.calls + breakdown.computation));
+    }
+}
@@ -6,4 +6,7 @@ pub mod state_diff;
 pub mod deal;
 pub mod balance_change;
 pub mod helper_contract;
-pub mod block;
\ No newline at end of file
+pub mod block;
+pub mod inspector;
+pub mod slot_label;
+pub mod summary;
\ No newline at end of file
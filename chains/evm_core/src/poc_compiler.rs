@@ -1,27 +1,125 @@
-use std::path::PathBuf;
-use anyhow::{bail, Result};
+use std::{collections::BTreeMap as Map, path::PathBuf};
+use anyhow::{anyhow, bail, Result};
+use alloy_json_abi::JsonAbi;
 use revm::primitives::Bytecode;
 use foundry_compilers::{
     artifacts::{Settings, SettingsMetadata, BytecodeHash}, 
-    EvmVersion, Project, Solc, SolcConfig
+    EvmVersion, ConfigurableArtifacts, Project, ProjectCompileOutput, Solc, SolcConfig
 };
 
-pub fn compile_poc(file: impl Into<PathBuf>) -> Result<Bytecode> {
+/// The solc version new pocs are compiled with
+pub const SOLC_VERSION: &str = "0.8.20";
+/// The evm version new pocs are compiled for
+pub const EVM_VERSION: EvmVersion = EvmVersion::Shanghai;
+
+/// The environment variable of a preinstalled solc to use instead of downloading one
+pub const SOLC_PATH_VAR: &str = "SOLC_PATH";
+
+/// The solc binary at `path`, it must be the requested version.
+fn pinned_solc(path: PathBuf, solc_version: &str) -> Result<Solc> {
+    let solc = Solc::new(&path);
+    let version = solc.version().map_err(|err| anyhow!("Failed to run solc {:?}: {}", path, err))?;
+    let version = format!("{}.{}.{}", version.major, version.minor, version.patch);
+    if version != solc_version {
+        bail!("The solc {:?} is version {}, the poc needs {}", path, version, solc_version)
+    }
+    Ok(solc)
+}
+
+/// The deployed code of the `Exploit` contract and its abi.
+#[derive(Debug, Clone)]
+pub struct CompiledPoc {
+    pub bytecode: Bytecode,
+    /// Names the custom errors of the poc when it reverts
+    pub abi: Option<JsonAbi>,
+}
+
+pub fn compile_poc(file: impl Into<PathBuf>) -> Result<CompiledPoc> {
+    compile_poc_with(file, SOLC_VERSION, &EVM_VERSION.to_string())
+}
+
+/// Compiles the poc file, with its imports.
+fn compile_file(file: impl Into<PathBuf>, solc_version: &str, evm_version: &str) -> Result<ProjectCompileOutput<ConfigurableArtifacts>> {
+    let file = file.into();
+    let evm_version: EvmVersion = evm_version.parse().map_err(|err| anyhow!("{}", err))?;
     let mut settings = Settings::default();
-    settings.evm_version = Some(EvmVersion::Shanghai);
+    settings.evm_version = Some(evm_version);
     let metadata =  SettingsMetadata::new(BytecodeHash::None, false);
     settings.metadata = Some(metadata);
     let solc_config = SolcConfig { settings: settings };
-    let solc = Solc::find_or_install_svm_version("0.8.20").expect("could not install solc");
-    let project = Project::builder().solc(solc).solc_config(solc_config).offline().ephemeral().no_artifacts().build().unwrap();
-    let mut output = project.compile_files(vec![file, ]).unwrap();
+    let solc = match std::env::var_os(SOLC_PATH_VAR) {
+        Some(path) => pinned_solc(path.into(), solc_version)?,
+        None => Solc::find_or_install_svm_version(solc_version)
+            .map_err(|err| anyhow!("Failed to install solc {}: {}", solc_version, err))?,
+    };
+    let project = Project::builder().solc(solc).solc_config(solc_config).offline().ephemeral().no_artifacts().build()
+        .map_err(|err| anyhow!("Failed to set up the solc project: {}", err))?;
+    let output = project.compile_files(vec![file.clone()])
+        .map_err(|err| anyhow!("Failed to compile {:?}: {}", file, err))?;
     if output.has_compiler_errors() {
-        bail!("Faield to build Solidity contracts")
+        bail!("Failed to build Solidity contracts")
     }
-    
-    let contract = output.remove_first("Exploit");
-    if contract.is_none() {
+    Ok(output)
+}
+
+/// Compiles the poc with the solc and evm version recorded in a proof.
+pub fn compile_poc_with(file: impl Into<PathBuf>, solc_version: &str, evm_version: &str) -> Result<CompiledPoc> {
+    let mut output = compile_file(file, solc_version, evm_version)?;
+    let Some(contract) = output.remove_first("Exploit") else {
         bail!("Can not find 'Exploit' contract")
+    };
+    let Some(bytecode) = contract.deployed_bytecode.and_then(|x| x.bytecode) else {
+        bail!("'Exploit' contract has no deployed bytecode")
+    };
+    let Some(code) = bytecode.object.into_bytes() else {
+        bail!("'Exploit' contract has unlinked libraries, make the library functions internal")
+    };
+    if code.is_empty() {
+        bail!("'Exploit' contract is abstract or an interface")
+    }
+    Ok(CompiledPoc {
+        bytecode: Bytecode::new_raw(code),
+        abi: contract.abi,
+    })
+}
+
+/// The deployed code of every contract of the poc file and its imports, by contract name,
+/// e.g. the helpers `Exploit` deploys, to etch them elsewhere. Interfaces, abstract contracts
+/// and contracts with unlinked libraries have no deployable code and are left out.
+pub fn compile_poc_all(file: impl Into<PathBuf>) -> Result<Map<String, Bytecode>> {
+    let output = compile_file(file, SOLC_VERSION, &EVM_VERSION.to_string())?;
+    let mut contracts = Map::new();
+    for (id, contract) in output.into_artifacts() {
+        let code = contract.deployed_bytecode
+            .and_then(|x| x.bytecode)
+            .and_then(|bytecode| bytecode.object.into_bytes());
+        match code {
+            Some(code) if !code.is_empty() => {
+                contracts.insert(id.name, Bytecode::new_raw(code));
+            }
+            _ => {}
+        }
+    }
+    Ok(contracts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn every_contract_of_the_poc_is_returned() {
+        let dir = std::env::temp_dir().join(format!("zkprover-{}-poc-all", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("Exploit.sol");
+        fs::write(&file, "pragma solidity ^0.8.0;\n\
+            contract Helper { function value() external pure returns (uint256) { return 1; } }\n\
+            contract Exploit { function exploit() external { new Helper(); } }\n").unwrap();
+        let contracts = compile_poc_all(&file);
+        fs::remove_dir_all(&dir).unwrap();
+        let contracts = contracts.unwrap();
+        assert_eq!(contracts.keys().collect::<Vec<_>>(), ["Exploit", "Helper"]);
+        assert!(contracts.values().all(|code| !code.is_empty()));
     }
-    Ok(Bytecode::new_raw(contract.unwrap().deployed_bytecode.unwrap().bytecode.unwrap().object.into_bytes().unwrap()))
-}
\ No newline at end of file
+}
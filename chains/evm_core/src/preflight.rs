@@ -1,32 +1,174 @@
-use anyhow::{bail, Result};
-use revm::primitives::{AccountInfo, Bytecode, ExecutionResult, TransactTo, U256, SpecId};
-use revm::Evm;
+use anyhow::{bail, Context, Result};
+use std::{fs, path::Path};
+use revm::primitives::{AccountInfo, Address, Bytecode, Bytes, ExecutionResult, ResultAndState, B256, U256, SpecId};
+use revm::{db::CacheDB, inspector_handle_register, Database, Evm};
+use alloy_json_abi::JsonAbi;
 use alloy_provider::{Network, Provider};
+use alloy_rpc_types::AccessList;
 use alloy_transport::Transport;
-use log::info;
-use bridge::{ExploitInput, CALL_EXPLOIT_DATA, DEFAULT_CALLER, DEFAULT_CONTRACT_ADDRESS, DEFAULT_GAS_LIMIT};
+use log::{debug, info};
+use bridge::{
+    apply_setup_txs, exploit_env, CALL_EXPLOIT_DATA, exploit_outcome, gas_refunded, try_sim_exploit, ExploitInput, MemDB, SetupTx, DEFAULT_CALLER, DEFAULT_CONTRACT_ADDRESS, MAX_GAS_LIMIT
+};
 
+use crate::balance_change::{compute_asset_change, compute_net_gain};
 use crate::block::BlockHeader;
-use crate::db::{JsonBlockCacheDB, ProxyDB};
+use crate::db::{ChainSpec, DatabaseRef, JsonBlockCacheDB, ProxyDB};
+use crate::deal::{token_deal_storage, DealRecord};
+use crate::inspector::{format_call_tree, CallTrace, CallTracer, GasBreakdown, PreflightInspector};
+use crate::utils::{decode_revert, explain_evm_error};
 
 
+/// What the preflight observed while running the exploit.
+#[derive(Debug, Clone, Default)]
+pub struct PreflightReport {
+    /// Net of refunds, as charged to the caller
+    pub gas_used: u64,
+    pub gas_refunded: u64,
+    /// The exploit reads `tx.origin`, so it depends on being sent by [DEFAULT_CALLER]
+    pub reads_origin: bool,
+    /// The accounts and slots the exploit touched
+    pub access_list: AccessList,
+    /// [None] unless [PreflightConfig::gas_breakdown] is set
+    pub gas_breakdown: Option<GasBreakdown>,
+    /// In-window blocks whose `BLOCKHASH` the exploit read as zero
+    pub zero_block_hashes: Vec<u64>,
+    /// Empty unless [PreflightConfig::trace] is set
+    pub calls: Vec<CallTrace>,
+}
+
+impl PreflightReport {
+    fn new(result: &ExecutionResult, inspector: &PreflightInspector, access_list: AccessList) -> Self {
+        Self {
+            gas_used: result.gas_used(),
+            gas_refunded: gas_refunded(result),
+            reads_origin: inspector.reads_origin,
+            access_list,
+            gas_breakdown: inspector.gas_breakdown,
+            zero_block_hashes: inspector.zero_block_hashes.clone(),
+            calls: Vec::new(),
+        }
+    }
+
+    /// Human readable warnings about the exploit
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if self.reads_origin {
+            warnings.push(format!(
+                "the exploit reads `tx.origin`, it may behave differently when not sent by {}",
+                DEFAULT_CALLER
+            ));
+        }
+        for number in self.zero_block_hashes.iter() {
+            warnings.push(format!(
+                "`BLOCKHASH({})` returned zero although the block is within the last 256, its hash may be missing",
+                number
+            ));
+        }
+        warnings
+    }
+}
+
+/// How the exploit tx is set up on top of the fork.
+#[derive(Debug, Clone, Default)]
+pub struct PreflightConfig {
+    pub chain_id: u64,
+    /// The ether balance of the poc contract
+    pub initial_balance: U256,
+    /// The gas limit of the exploit tx, defaults to the block gas limit
+    pub gas_limit: Option<u64>,
+    /// Blob versioned hashes of the exploit tx, requires a Cancun block
+    pub blob_hashes: Vec<B256>,
+    pub max_fee_per_blob_gas: Option<U256>,
+    /// Replaces the block's coinbase, for exploits paying a hypothetical builder
+    pub coinbase: Option<Address>,
+    /// The abi of the poc, to name its custom errors when it reverts
+    pub poc_abi: Option<JsonAbi>,
+    /// Txs applied on top of the fork before the exploit
+    pub setup_txs: Vec<SetupTx>,
+    /// Account the gas of the exploit by opcode class in the report
+    pub gas_breakdown: bool,
+    /// The gas price of the exploit tx, defaults to the base fee
+    pub gas_price: Option<U256>,
+    /// Paid to the coinbase on top of the base fee, ignored when `gas_price` is set
+    pub priority_fee: Option<U256>,
+    /// When the exploit fails, add its calls up to the failure to the error
+    pub trace_revert: bool,
+    /// Record the calls of the exploit in the report
+    pub trace: bool,
+    /// The exploit must revert instead of succeeding
+    pub expect_revert: bool,
+    /// Call the poc contract with this instead of `exploit()`
+    pub calldata: Option<Bytes>,
+    /// The ether deals set the balance of their accounts, the poc contract's is `initial_balance`
+    pub deals: Vec<DealRecord>,
+}
+
 pub fn build_input<T, N, P>(
     contract: Bytecode,
     header: BlockHeader,
     rpc_db: &JsonBlockCacheDB<T, N, P>,
-    initial_balance: U256,
-) -> Result<ExploitInput>
+    config: &PreflightConfig,
+) -> Result<(ExploitInput, PreflightReport)>
 where
 T: Transport + Clone, N: Network, P: Provider<T, N>,
 {
+    let gas_limit = config.gas_limit.unwrap_or(header.gas_limit.min(MAX_GAS_LIMIT));
+    if gas_limit > header.gas_limit {
+        bail!("The gas limit {} is higher than the block gas limit {}", gas_limit, header.gas_limit)
+    }
+    let spec_id = ChainSpec::new(config.chain_id).spec_id(header.number, header.timestamp)?;
+    // blob txs only exist since Cancun
+    if !(config.blob_hashes.is_empty() && config.max_fee_per_blob_gas.is_none()) && spec_id < SpecId::CANCUN {
+        bail!("Blob hashes need a Cancun block, block {} runs {:?}", header.number, spec_id)
+    }
+    let mut block_env = header.into_block_env();
+    if let Some(coinbase) = config.coinbase {
+        block_env.coinbase = coinbase;
+    }
+    // the committed gas price is the effective one, the exploit tx is sent as a legacy tx
+    let gas_price = match (config.gas_price, config.priority_fee) {
+        (Some(gas_price), _) => gas_price,
+        (None, Some(priority_fee)) => block_env.basefee + priority_fee,
+        (None, None) => block_env.basefee,
+    };
+    if gas_price < block_env.basefee {
+        bail!("The gas price {} is lower than the base fee {}", gas_price, block_env.basefee)
+    }
+    let mut input = ExploitInput {
+        db: MemDB::default(),
+        block_env: block_env.clone(),
+        spec_id: spec_id,
+        chain_id: config.chain_id,
+        gas_price: gas_price,
+        gas_limit: gas_limit,
+        blob_hashes: config.blob_hashes.clone(),
+        max_fee_per_blob_gas: config.max_fee_per_blob_gas,
+        setup_txs: config.setup_txs.clone(),
+        expect_revert: config.expect_revert,
+        calldata: config.calldata.clone().unwrap_or(CALL_EXPLOIT_DATA),
+    };
+
     let mut db = ProxyDB::new(rpc_db);
     // init account
     db.insert_account_info(
         DEFAULT_CONTRACT_ADDRESS,
-        AccountInfo::new(initial_balance, 1, contract.hash_slow(), contract.clone()),
+        AccountInfo::new(config.initial_balance, 1, contract.hash_slow(), contract.clone()),
     );
+    for deal in config.deals.iter().filter(|deal| deal.is_ether() && deal.address != DEFAULT_CONTRACT_ADDRESS) {
+        let mut info = rpc_db.basic_ref(deal.address)?.unwrap_or_default();
+        // in order, so the last deal of an account wins
+        info.balance = deal.balance;
+        db.insert_account_info(deal.address, info);
+    }
+    for (token, storage) in token_deal_storage(rpc_db, &config.deals)? {
+        for (index, value) in storage {
+            db.insert_account_storage(token, index, value);
+        }
+    }
+    // the caller only holds enough ether to pay for the gas
     db.insert_account_info(DEFAULT_CALLER,  AccountInfo{
-        nonce: 1, ..Default::default()
+        balance: input.caller_balance(), nonce: 1, ..Default::default()
     });
 
     // apply patch
@@ -36,38 +178,434 @@ T: Transport + Clone, N: Network, P: Provider<T, N>,
     //     }
     // }
 
-    let block_env = header.into_block_env();
-    let spec_id = SpecId::SHANGHAI;
+    if let Err(err) = apply_setup_txs(&mut db, &input) {
+        bail!("Failed to apply the setup txs: {}", explain_evm_error(&err))
+    }
 
     let mut evm = Evm::builder()
         .with_db(db)
-        .with_spec_id(spec_id)
-        .with_block_env(block_env.clone())
-        .modify_tx_env(|tx| {
-            tx.caller = DEFAULT_CALLER;
-            tx.transact_to = TransactTo::Call(DEFAULT_CONTRACT_ADDRESS);
-            tx.data = CALL_EXPLOIT_DATA;
-            tx.value = U256::ZERO;
-            tx.gas_limit = DEFAULT_GAS_LIMIT;
-        })
+        .with_external_context(PreflightInspector::new(config.gas_breakdown))
+        .with_env(exploit_env(&input))
+        .with_spec_id(input.spec_id)
+        .append_handler_register(inspector_handle_register)
         .build();
 
-    let result_and_state = evm.transact_preverified()?;
+    let result_and_state = match evm.transact_preverified() {
+        Ok(result_and_state) => result_and_state,
+        Err(err) => bail!("Failed to execute the exploit: {}", explain_evm_error(&err)),
+    };
     
-    match result_and_state.result {
-        ExecutionResult::Success{gas_used, ..} => {
-            info!("Success! Gas used: {}", gas_used);
+    // the db holds the state after the setup txs, run the failed exploit again to trace it
+    let trace = if config.trace_revert && exploit_outcome(&input, &result_and_state.result).is_err() {
+        format!("\n{}", format_call_tree(&trace_exploit(evm.db_mut(), &input), config.poc_abi.as_ref()))
+    } else {
+        String::new()
+    };
+    match &result_and_state.result {
+        ExecutionResult::Success{gas_used, ..} if config.expect_revert => {
+            bail!("The exploit succeeded but a revert is expected, gas used: {}{}", gas_used, trace)
+        }
+        ExecutionResult::Success{gas_used, gas_refunded, ..} => {
+            info!("Success! Gas used: {}, refunded: {}", gas_used, gas_refunded);
+        }
+        ExecutionResult::Revert {gas_used, output} if config.expect_revert => {
+            info!("Reverted as expected: {}, gas used: {}", decode_revert(output, config.poc_abi.as_ref()), gas_used);
         }
-        ExecutionResult::Revert {gas_used, ..} => {
-            bail!("Revert, gas used: {}", gas_used)
+        ExecutionResult::Revert {gas_used, output} => {
+            bail!("Revert: {}, gas used: {}{}", decode_revert(output, config.poc_abi.as_ref()), gas_used, trace)
         }
         ExecutionResult::Halt { reason, gas_used } => {
-            bail!("Halt: {:#?}, gas used: {}", reason, gas_used)
+            bail!("Halt: {:#?}, gas used: {}{}", reason, gas_used, trace)
         }
     }
-    Ok(ExploitInput{
-        db: evm.db().into_memdb(),
-        block_env: block_env,
-        spec_id: spec_id
-    })
+    input.db = evm.db().into_memdb()?;
+    let mut report = PreflightReport::new(&result_and_state.result, &evm.context.external, evm.db().as_access_list());
+    if config.trace {
+        report.calls = trace_exploit(evm.db_mut(), &input);
+    }
+    let db = minimize_witness(&input, &result_and_state)?;
+    if let Some(address) = db.account_with_missing_code() {
+        bail!("The code of {} doesn't match its code hash", address)
+    }
+    Ok((ExploitInput { db, ..input }, report))
+}
+
+/// Runs the exploit over the db with a [CallTracer] and returns its calls, whatever the
+/// outcome.
+fn trace_exploit<DB: Database>(db: DB, input: &ExploitInput) -> Vec<CallTrace> {
+    let mut evm = Evm::builder()
+        .with_db(db)
+        .with_external_context(CallTracer::default())
+        .with_env(exploit_env(input))
+        .with_spec_id(input.spec_id)
+        .append_handler_register(inspector_handle_register)
+        .build();
+    let _ = evm.transact_preverified();
+    evm.context.external.calls.clone()
+}
+
+/// Replays the exploit over the traced state only and keeps the accounts, slots and block
+/// hashes the replay actually reads. The replay must reproduce the preflight result,
+/// otherwise the traced state is not a valid witness for the guest.
+pub fn minimize_witness(input: &ExploitInput, expected: &ResultAndState) -> Result<MemDB> {
+    let mut db = ProxyDB::new(&input.db);
+    if let Err(err) = apply_setup_txs(&mut db, input) {
+        bail!("Failed to replay the setup txs over the traced state: {}", explain_evm_error(&err))
+    }
+    let mut evm = Evm::builder()
+        .with_db(db)
+        .with_env(exploit_env(input))
+        .with_spec_id(input.spec_id)
+        .build();
+
+    let replay = match evm.transact() {
+        Ok(result_and_state) => result_and_state,
+        Err(err) => bail!("Failed to replay the exploit over the traced state: {}", explain_evm_error(&err)),
+    };
+    if replay.result != expected.result || replay.state != expected.state {
+        bail!("Replaying the exploit over the traced state diverges from the preflight")
+    }
+    let db = evm.db().into_memdb()?;
+    debug!(
+        "witness minimized, accounts: {} -> {}, block hashes: {} -> {}",
+        input.db.accounts.len(), db.accounts.len(),
+        input.db.block_hashes.len(), db.block_hashes.len(),
+    );
+    Ok(db)
+}
+
+
+/// The account the archive check reads: the wrapped native token where it is known, which
+/// always holds code and ether, otherwise the coinbase of the fork block, which was just paid
+/// the fees of that block on any chain.
+pub fn archive_probe(chain_spec: &ChainSpec, header: &BlockHeader) -> Address {
+    chain_spec.wrapped_native().unwrap_or(header.author)
+}
+
+
+/// Fails when the node doesn't have the state of the fork block. Some public rpcs prune old
+/// state and answer zeros instead of an error, so an account known to exist at that block
+/// is probed, see [archive_probe].
+pub fn check_archive_state<DB: DatabaseRef>(db: &DB, chain_spec: &ChainSpec, header: &BlockHeader) -> Result<()>
+where
+DB::Error: std::fmt::Debug,
+{
+    let probe = archive_probe(chain_spec, header);
+    let info = db.basic_ref(probe)
+        .map_err(|e| anyhow::anyhow!("could not read the archive probe {}: {:?}", probe, e))?
+        .unwrap_or_default();
+    if info.balance.is_zero() && info.nonce == 0 && info.is_empty_code_hash() {
+        bail!(
+            "The rpc reads {} as empty at this block, it may not keep the state that far back, use an archive node or pass --skip-archive-check",
+            probe
+        )
+    }
+    Ok(())
+}
+
+
+/// Loads the setup txs from a json array of `{from, to, data, value}`.
+pub fn load_setup_txs(path: impl AsRef<Path>) -> Result<Vec<SetupTx>> {
+    let path = path.as_ref();
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read setup tx file {:?}", path))?;
+    serde_json::from_str(&content).with_context(|| format!("Malformed setup tx file {:?}", path))
+}
+
+
+/// Builds the input a second time and fails unless it serializes to the same bytes, a
+/// guard against the input depending on the order of the reads or of a map. The second
+/// build starts from an empty cache, so it also catches an rpc serving different state.
+pub fn check_reproducible<T, N, P>(
+    input: &ExploitInput,
+    contract: Bytecode,
+    header: BlockHeader,
+    rpc_db: &JsonBlockCacheDB<T, N, P>,
+    config: &PreflightConfig,
+) -> Result<()>
+where
+T: Transport + Clone, N: Network, P: Provider<T, N> + Clone,
+{
+    // a warm cache would replay the reads of the first build, start over from the rpc
+    let (again, _) = build_input(contract, header, &rpc_db.fresh(), config)?;
+    if serde_json::to_vec(input)? != serde_json::to_vec(&again)? {
+        bail!("The input is not reproducible, building it twice gave different bytes")
+    }
+    Ok(())
+}
+
+/// Fails when the traced state is too large to be proven in a reasonable time.
+pub fn check_state_entries(input: &ExploitInput, max_state_entries: usize) -> Result<()> {
+    let entries = input.db.state_entries();
+    if entries > max_state_entries {
+        bail!(
+            "The exploit touches {} state entries (accounts, storage slots and block hashes), more than the limit {}, proving it is impractical",
+            entries, max_state_entries
+        )
+    }
+    Ok(())
+}
+
+/// Fails when the exploit leaves the poc contract with no positive net gain in any token,
+/// so a run that extracts nothing stops before proving. Wrapping ether is not a gain.
+pub fn check_profit(input: &ExploitInput, chain_spec: &ChainSpec) -> Result<()> {
+    let result_and_state = match try_sim_exploit(input) {
+        Ok(result_and_state) => result_and_state,
+        Err(err) => bail!("Failed to run the exploit: {}", explain_evm_error(&err)),
+    };
+    let asset_change = compute_asset_change(&vec![DEFAULT_CONTRACT_ADDRESS], &input.db, result_and_state.state)?;
+    let net_gain = compute_net_gain(&asset_change, chain_spec.wrapped_native());
+    if !net_gain.iter().any(|gain| gain.amount.is_positive()) {
+        bail!("The exploit has no net gain in any token")
+    }
+    Ok(())
+}
+/// Runs the exploit of a proven input again, offline, recording its calls. The poc code
+/// is not committed by the guest and must be put back into `input.db` first.
+pub fn resim_exploit(input: &ExploitInput) -> Result<(ResultAndState, CallTracer)> {
+    let mut db = CacheDB::new(&input.db);
+    if let Err(err) = apply_setup_txs(&mut db, input) {
+        bail!("Failed to re-simulate the setup txs: {}", explain_evm_error(&err))
+    }
+    let mut evm = Evm::builder()
+        .with_db(db)
+        .with_external_context(CallTracer::default())
+        .with_env(exploit_env(input))
+        .with_spec_id(input.spec_id)
+        .append_handler_register(inspector_handle_register)
+        .build();
+
+    let result_and_state = match evm.transact() {
+        Ok(result_and_state) => result_and_state,
+        Err(err) => bail!("Failed to re-simulate the exploit: {}", explain_evm_error(&err)),
+    };
+    let tracer = evm.context.external.clone();
+    Ok((result_and_state, tracer))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{BlockchainDbMeta, JsonBlockCacheData};
+    use alloy_provider::ProviderBuilder;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// The block the exploits below run in, its base fee is 7 wei
+    fn fork_header() -> BlockHeader {
+        BlockHeader {
+            number: 18_000_000,
+            timestamp: 1_700_000_000,
+            base_fee_per_gas: U256::from(7),
+            ..header(Address::repeat_byte(0xc0))
+        }
+    }
+
+    /// The rpc cache of the block, with the accounts every exploit reads
+    fn cache(header: BlockHeader) -> JsonBlockCacheData {
+        let mut cache = JsonBlockCacheData {
+            meta: BlockchainDbMeta::new(ChainSpec::mainnet(), header.clone()),
+            accounts: Default::default(),
+            storage: Default::default(),
+            block_hashes: Default::default(),
+        };
+        for address in [DEFAULT_CONTRACT_ADDRESS, DEFAULT_CALLER, header.author] {
+            cache.accounts.insert(address, AccountInfo::default());
+        }
+        cache
+    }
+
+    /// Builds the input of a poc running `code` over the cache. Nothing listens on the rpc,
+    /// so a read the cache misses fails.
+    fn build(cache: JsonBlockCacheData, code: &str, config: &PreflightConfig) -> Result<(ExploitInput, PreflightReport)> {
+        static BUILDS: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "zkprover-{}-preflight-{}.json", std::process::id(), BUILDS.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&path, serde_json::to_string(&cache)?)?;
+        let provider = ProviderBuilder::new().on_http("http://127.0.0.1:1".parse()?)?;
+        let header = cache.meta.header.clone();
+        let db = JsonBlockCacheDB::new(provider, cache.meta, Some(path.clone()));
+        let built = build_input(Bytecode::new_raw(hex::decode(code)?.into()), header, &db, config);
+        drop(db);
+        fs::remove_file(&path)?;
+        built
+    }
+
+    fn mainnet() -> PreflightConfig {
+        PreflightConfig { chain_id: 1, ..Default::default() }
+    }
+
+    fn header(author: Address) -> BlockHeader {
+        BlockHeader {
+            parent_hash: B256::ZERO,
+            uncles_hash: B256::ZERO,
+            author,
+            state_root: B256::ZERO,
+            transactions_root: B256::ZERO,
+            receipts_root: B256::ZERO,
+            logs_bloom: Default::default(),
+            difficulty: U256::ZERO,
+            number: 1,
+            gas_limit: 30_000_000,
+            gas_used: 0,
+            timestamp: 0,
+            extra_data: Bytes::new(),
+            mix_hash: B256::ZERO,
+            nonce: Default::default(),
+            base_fee_per_gas: U256::ZERO,
+            withdrawals_root: None,
+            blob_gas_used: None,
+            excess_blob_gas: None,
+            parent_beacon_block_root: None,
+        }
+    }
+
+    #[test]
+    fn archive_check_fails_when_the_probe_reads_empty() {
+        let coinbase = Address::repeat_byte(0xc0);
+        let chain_spec = ChainSpec::new(10);
+        let mut db = MemDB::default();
+        db.accounts.insert(coinbase, Default::default());
+        assert!(check_archive_state(&db, &chain_spec, &header(coinbase)).is_err());
+    }
+
+    #[test]
+    fn archive_check_probes_the_coinbase_off_mainnet() {
+        let coinbase = Address::repeat_byte(0xc0);
+        let chain_spec = ChainSpec::new(10);
+        assert_eq!(archive_probe(&chain_spec, &header(coinbase)), coinbase);
+
+        let mut db = MemDB::default();
+        db.accounts.insert(coinbase, bridge::AccountStorage {
+            info: AccountInfo { balance: U256::from(1), ..Default::default() },
+            ..Default::default()
+        });
+        check_archive_state(&db, &chain_spec, &header(coinbase)).unwrap();
+    }
+
+    #[test]
+    fn committed_gas_price_is_the_base_fee_without_a_priority_fee() {
+        let (input, _) = build(cache(fork_header()), "00", &mainnet()).unwrap();
+        assert_eq!(input.gas_price, U256::from(7));
+        assert_eq!(input.block_env.basefee, U256::from(7));
+
+        let config = PreflightConfig { priority_fee: Some(U256::from(2)), ..mainnet() };
+        assert_eq!(build(cache(fork_header()), "00", &config).unwrap().0.gas_price, U256::from(9));
+        let config = PreflightConfig { gas_price: Some(U256::from(6)), ..mainnet() };
+        assert!(build(cache(fork_header()), "00", &config).is_err());
+    }
+
+    #[test]
+    fn minimized_witness_is_smaller_and_runs_the_same() {
+        let (input, _) = build(cache(fork_header()), "00", &mainnet()).unwrap();
+        let expected = try_sim_exploit(&input).unwrap();
+        let mut traced = input.clone();
+        traced.db.accounts.insert(Address::repeat_byte(9), bridge::AccountStorage::default());
+
+        let db = minimize_witness(&traced, &expected).unwrap();
+        assert!(db.accounts.len() < traced.db.accounts.len());
+        assert_eq!(db, input.db);
+        assert_eq!(try_sim_exploit(&ExploitInput { db, ..traced }).unwrap(), expected);
+    }
+
+    #[test]
+    fn reading_the_origin_is_reported() {
+        // ORIGIN POP STOP
+        let (_, report) = build(cache(fork_header()), "325000", &mainnet()).unwrap();
+        assert!(report.reads_origin);
+        assert!(report.warnings()[0].contains("tx.origin"));
+
+        let (_, report) = build(cache(fork_header()), "00", &mainnet()).unwrap();
+        assert!(!report.reads_origin);
+        assert!(report.warnings().is_empty());
+    }
+
+    #[test]
+    fn state_heavy_poc_hits_the_entry_limit() {
+        let mut cache = cache(fork_header());
+        // SLOAD the slots 0 to 9 of the poc, then STOP
+        let mut code = String::new();
+        for slot in 0..10u8 {
+            code.push_str(&format!("60{:02x}5450", slot));
+            cache.storage.entry(DEFAULT_CONTRACT_ADDRESS).or_default().insert(U256::from(slot), U256::from(slot));
+        }
+        code.push_str("00");
+        let (input, _) = build(cache, &code, &mainnet()).unwrap();
+
+        let entries = input.db.state_entries();
+        assert!(entries > 10);
+        check_state_entries(&input, entries).unwrap();
+        let err = check_state_entries(&input, 10).unwrap_err();
+        assert!(err.to_string().contains(&format!("touches {} state entries", entries)));
+    }
+
+    #[test]
+    fn gas_limit_follows_the_block_up_to_the_cap() {
+        let (input, _) = build(cache(fork_header()), "00", &mainnet()).unwrap();
+        assert_eq!(input.gas_limit, 30_000_000);
+
+        // an Arbitrum block
+        let header = BlockHeader { gas_limit: 1_125_899_906_842_624, ..fork_header() };
+        let (input, _) = build(cache(header.clone()), "00", &mainnet()).unwrap();
+        assert_eq!(input.gas_limit, MAX_GAS_LIMIT);
+        let config = PreflightConfig { gas_limit: Some(500_000_000), ..mainnet() };
+        assert_eq!(build(cache(header), "00", &config).unwrap().0.gas_limit, 500_000_000);
+
+        let config = PreflightConfig { gas_limit: Some(30_000_001), ..mainnet() };
+        assert!(build(cache(fork_header()), "00", &config).is_err());
+    }
+
+    #[test]
+    fn poc_reads_the_chain_id_of_the_config() {
+        // CHAINID PUSH1 1 EQ PUSH1 0x0b JUMPI PUSH1 0 DUP1 REVERT JUMPDEST STOP
+        let code = "46600114600b57600080fd5b00";
+        let (input, _) = build(cache(fork_header()), code, &mainnet()).unwrap();
+        assert_eq!(input.chain_id, 1);
+        assert_eq!(exploit_env(&input).cfg.chain_id, 1);
+
+        let sepolia = PreflightConfig { chain_id: 11155111, ..Default::default() };
+        let err = build(cache(fork_header()), code, &sepolia).unwrap_err();
+        assert!(err.to_string().starts_with("Revert"));
+    }
+
+    #[test]
+    fn zero_hash_of_a_block_in_the_window_is_reported() {
+        let header = fork_header();
+        let mut cache = cache(header.clone());
+        cache.block_hashes.insert(header.number - 1, B256::ZERO);
+        // BLOCKHASH(NUMBER - 1) POP BLOCKHASH(NUMBER - 300) POP STOP
+        let (_, report) = build(cache, "60014303405061012c4303405000", &mainnet()).unwrap();
+        assert_eq!(report.zero_block_hashes, vec![header.number - 1]);
+        assert_eq!(report.warnings().len(), 1);
+    }
+
+    #[test]
+    fn ether_deals_set_the_balance_not_the_storage() {
+        let receiver = Address::repeat_byte(0x42);
+        let mut cache = cache(fork_header());
+        cache.accounts.insert(receiver, AccountInfo::from_balance(U256::from(5)));
+        let config = PreflightConfig {
+            initial_balance: U256::from(3),
+            deals: vec![
+                DealRecord { address: DEFAULT_CONTRACT_ADDRESS, token: Address::ZERO, balance: U256::from(3) },
+                DealRecord { address: receiver, token: Address::ZERO, balance: U256::from(1000) },
+            ],
+            ..mainnet()
+        };
+        // BALANCE(receiver) POP STOP
+        let code = format!("73{}315000", hex::encode(receiver));
+        let (input, _) = build(cache, &code, &config).unwrap();
+        assert_eq!(input.db.accounts[&receiver].info.balance, U256::from(1000));
+        assert!(input.db.accounts[&receiver].storage.is_empty());
+        assert_eq!(input.db.accounts[&DEFAULT_CONTRACT_ADDRESS].info.balance, U256::from(3));
+        assert!(input.db.accounts[&DEFAULT_CONTRACT_ADDRESS].storage.is_empty());
+    }
+
+    #[test]
+    fn poc_without_a_gain_fails_the_profit_check() {
+        let (input, _) = build(cache(fork_header()), "00", &mainnet()).unwrap();
+        let err = check_profit(&input, &ChainSpec::mainnet()).unwrap_err();
+        assert_eq!(err.to_string(), "The exploit has no net gain in any token");
+    }
 }
@@ -0,0 +1,105 @@
+use std::collections::{BTreeMap, HashMap};
+use alloy_primitives::{b256, keccak256, Address, B256, U256};
+
+use crate::state_diff::StateDiff;
+
+/// Mappings declared in the first slots of a contract are labeled
+const MAX_MAPPING_SLOT: u64 = 16;
+
+/// Well known proxy slots, see EIP-1967
+const EIP1967_SLOTS: [(B256, &str); 3] = [
+    (b256!("360894a13ba1a3210667c828492db98dca3e2076cc3735a920a3ca505d382bbc"), "eip1967.proxy.implementation"),
+    (b256!("b53127684a568b3173ae13b9f8a6016e243e63b6e8ee1178d6a717850b5d6103"), "eip1967.proxy.admin"),
+    (b256!("a3f0ad74e5423aebfd80d3ef4346578335a9a72aeaee59ff6cb3582b35133d50"), "eip1967.proxy.beacon"),
+];
+
+/// The storage slot of `mapping[key]` for a mapping declared at `base`
+fn mapping_slot(key: B256, base: B256) -> B256 {
+    keccak256([key.as_slice(), base.as_slice()].concat())
+}
+
+/// Labels the changed storage slots of the state diff whose layout can be derived: the
+/// EIP-1967 proxy slots, and entries of mappings (e.g. ERC-20 balances and allowances)
+/// keyed by one or two of the `keys` addresses, labeled as `mapping<base slot>[key]`.
+pub fn label_slots(state_diff: &StateDiff, keys: &[Address]) -> BTreeMap<Address, BTreeMap<U256, String>> {
+    let mut known: HashMap<U256, String> = EIP1967_SLOTS
+        .iter()
+        .map(|(slot, label)| (U256::from_be_bytes(slot.0), label.to_string()))
+        .collect();
+    for base in 0..=MAX_MAPPING_SLOT {
+        let base_word = B256::from(U256::from(base).to_be_bytes::<32>());
+        for key in keys {
+            let slot = mapping_slot(key.into_word(), base_word);
+            known.insert(U256::from_be_bytes(slot.0), format!("mapping{}[{}]", base, key));
+            for inner_key in keys {
+                let inner_slot = mapping_slot(inner_key.into_word(), slot);
+                known.insert(
+                    U256::from_be_bytes(inner_slot.0),
+                    format!("mapping{}[{}][{}]", base, key, inner_key),
+                );
+            }
+        }
+    }
+
+    let mut labels = BTreeMap::new();
+    for (address, account) in state_diff.iter() {
+        let account_labels: BTreeMap<U256, String> = account
+            .storage
+            .keys()
+            .filter_map(|slot| known.get(slot).map(|label| (*slot, label.clone())))
+            .collect();
+        if !account_labels.is_empty() {
+            labels.insert(*address, account_labels);
+        }
+    }
+    labels
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state_diff::{AccountDiff, Delta};
+
+    /// The slot of `mapping[key]` the way solidity lays it out, `keccak256(abi.encode(key, base))`
+    fn solidity_slot(key: Address, base: U256) -> U256 {
+        let mut preimage = [0u8; 64];
+        preimage[12..32].copy_from_slice(key.as_slice());
+        preimage[32..].copy_from_slice(&base.to_be_bytes::<32>());
+        U256::from_be_bytes(keccak256(preimage).0)
+    }
+
+    #[test]
+    fn labels_proxy_slots_and_mapping_entries() {
+        let token = Address::with_last_byte(0x10);
+        let proxy = Address::with_last_byte(0x20);
+        let untouched = Address::with_last_byte(0x30);
+        let owner = Address::with_last_byte(0xaa);
+        let spender = Address::with_last_byte(0xbb);
+
+        let balance_slot = solidity_slot(owner, U256::ZERO);
+        let allowance_slot = solidity_slot(spender, solidity_slot(owner, U256::from(1)));
+        let implementation_slot = U256::from_be_bytes(EIP1967_SLOTS[0].0 .0);
+        let changed = Delta::Added(U256::from(1));
+
+        let mut state_diff = StateDiff::default();
+        let mut account = AccountDiff::default();
+        account.storage.insert(balance_slot, changed.clone());
+        account.storage.insert(allowance_slot, changed.clone());
+        account.storage.insert(U256::from(3), changed.clone());
+        state_diff.insert(token, account);
+        let mut account = AccountDiff::default();
+        account.storage.insert(implementation_slot, changed.clone());
+        state_diff.insert(proxy, account);
+        let mut account = AccountDiff::default();
+        account.storage.insert(U256::from(3), changed);
+        state_diff.insert(untouched, account);
+
+        let labels = label_slots(&state_diff, &[owner, spender]);
+        assert_eq!(labels[&token][&balance_slot], format!("mapping0[{}]", owner));
+        assert_eq!(labels[&token][&allowance_slot], format!("mapping1[{}][{}]", owner, spender));
+        assert_eq!(labels[&token].len(), 2);
+        assert_eq!(labels[&proxy][&implementation_slot], "eip1967.proxy.implementation");
+        assert!(!labels.contains_key(&untouched));
+    }
+}
@@ -11,6 +11,9 @@ pub struct ChangedType<T> {
     pub to: T,
 }
 
+/// The change of one value. In json the variant is a one-character tag, consumers match on it:
+/// `"="` unchanged, `{"+": v}` added, `{"-": v}` removed, `{"*": {"from": a, "to": b}}` changed.
+/// Don't rename the tags.
 #[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
 pub enum Delta<T> {
     #[default]
@@ -32,7 +35,9 @@ pub struct AccountDiff {
     pub storage: HashMap<U256, Delta<U256>>,
 }
 
-#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+/// Serialized as a bare map of address to account diff, e.g.
+/// `{"0x..": {"balance": {"*": {"from": "0x1", "to": "0x2"}}, "nonce": "=", "code_hash": "=", "storage": {}}}`
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
 #[serde(transparent)]
 pub struct StateDiff(pub HashMap<Address, AccountDiff>);
 
@@ -116,4 +121,32 @@ pub fn compute_state_diff(state: &State, db: &MemDB) -> StateDiff {
     }
 
     state_diff
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every tag once, as consumers of the json read them
+    const FIXTURE: &str = r#"{
+        "0x00000000000000000000000000000000000000aa": {
+            "balance": {"*": {"from": "0x1", "to": "0x2"}},
+            "nonce": {"+": 1},
+            "code_hash": "=",
+            "storage": {"0x1": {"-": "0x5"}}
+        }
+    }"#;
+
+    #[test]
+    fn delta_tags_round_trip_through_the_fixture() {
+        let diff: StateDiff = serde_json::from_str(FIXTURE).unwrap();
+        let account = &diff[&Address::with_last_byte(0xaa)];
+        assert_eq!(account.balance, Delta::Changed(ChangedType { from: U256::from(1), to: U256::from(2) }));
+        assert_eq!(account.nonce, Delta::Added(1));
+        assert_eq!(account.code_hash, Delta::Unchanged);
+        assert_eq!(account.storage[&U256::from(1)], Delta::Removed(U256::from(5)));
+
+        let fixture: serde_json::Value = serde_json::from_str(FIXTURE).unwrap();
+        assert_eq!(serde_json::to_value(&diff).unwrap(), fixture);
+    }
+}
@@ -0,0 +1,69 @@
+use alloy_primitives::Address;
+use anyhow::Result;
+use bridge::ExploitOutput;
+use serde::{Deserialize, Serialize};
+
+use crate::inspector::CallTrace;
+use crate::balance_change::{compute_asset_change, compute_net_gain, AssetChange, TokenGain};
+use crate::state_diff::{compute_state_diff, StateDiff};
+
+/// What an exploit did, derived from its committed output
+#[derive(Clone, Debug, Serialize)]
+pub struct ExploitSummary {
+    pub gas_used: u64,
+    pub gas_refunded: u64,
+    /// The accounts whose state changed, sorted
+    pub touched: Vec<Address>,
+    pub state_diff: StateDiff,
+    pub asset_change: Vec<AssetChange>,
+    /// Ordered by account and token, see [compute_net_gain]
+    pub net_gain: Vec<TokenGain>,
+}
+
+/// Computes the state diff, asset change and net gain of an exploit output in one pass.
+/// `wrapped_native` is passed through to [compute_net_gain].
+pub fn summarize_exploit(output: &ExploitOutput, wrapped_native: Option<Address>) -> Result<ExploitSummary> {
+    let db = &output.input.db;
+    let state_diff = compute_state_diff(&output.state, db);
+    let mut touched: Vec<Address> = state_diff.keys().cloned().collect();
+    touched.sort();
+    let accounts: Vec<Address> = db.accounts.keys().cloned().collect();
+    let asset_change = compute_asset_change(&accounts, db, output.state.clone())?;
+    let net_gain = compute_net_gain(&asset_change, wrapped_native);
+    Ok(ExploitSummary {
+        gas_used: output.gas_used,
+        gas_refunded: output.gas_refunded,
+        touched: touched,
+        state_diff: state_diff,
+        asset_change: asset_change,
+        net_gain: net_gain,
+    })
+}
+
+/// How much state an exploit went through
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExploitStats {
+    /// The accounts the exploit touched
+    pub accounts: usize,
+    /// The slots of the committed state, the exploit read all of them
+    pub slots_read: usize,
+    /// The slots the exploit changed
+    pub slots_written: usize,
+    /// The calls the exploit made, [None] when it wasn't traced
+    pub calls: Option<usize>,
+}
+
+/// Counts the state of an exploit output, and its calls when a trace is given.
+pub fn compute_exploit_stats(output: &ExploitOutput, trace: Option<&[CallTrace]>) -> ExploitStats {
+    let state = &output.state;
+    ExploitStats {
+        accounts: state.values().filter(|account| account.is_touched()).count(),
+        slots_read: output.input.db.accounts.values().map(|account| account.storage.len()).sum(),
+        slots_written: state.values()
+            .flat_map(|account| account.storage.values())
+            .filter(|slot| slot.is_changed())
+            .count(),
+        // the tx itself is the call at depth 0
+        calls: trace.map(|calls| calls.iter().filter(|call| call.depth > 0).count()),
+    }
+}
@@ -1,5 +1,10 @@
-use anyhow::{Result, Context};
-use alloy_primitives::U256;
+use std::fmt::Debug;
+use anyhow::{anyhow, bail, Result, Context};
+use alloy_dyn_abi::{DynSolValue, JsonAbiExt, Specifier};
+use alloy_json_abi::{Function, JsonAbi};
+use alloy_primitives::{Address, Bytes, B256, I256, U256};
+use alloy_sol_types::decode_revert_reason;
+use revm::primitives::{EVMError, InvalidHeader, InvalidTransaction};
 
 #[cfg(not(target_arch = "wasm32"))]
 use tokio::runtime::{Handle, Runtime};
@@ -46,4 +51,242 @@ pub fn parse_ether_value(value: &str) -> Result<U256> {
             .context("Could not parse ether value from string")?
             .0
     })
-}
\ No newline at end of file
+}
+
+/// Encodes a call of the function with the signature, e.g. `attack(address,uint256)`, with
+/// the arguments given as strings the way `cast` takes them.
+pub fn encode_calldata(signature: &str, args: &[String]) -> Result<Bytes> {
+    let function = Function::parse(signature)
+        .map_err(|err| anyhow!("Malformed function signature `{}`: {}", signature, err))?;
+    if function.inputs.len() != args.len() {
+        bail!("`{}` takes {} arguments, {} given", signature, function.inputs.len(), args.len())
+    }
+    let values = function.inputs.iter().zip(args.iter())
+        .map(|(param, arg)| {
+            let ty = param.resolve()?;
+            ty.coerce_str(arg).with_context(|| format!("`{}` is not a valid {}", arg, ty.sol_type_name()))
+        })
+        .collect::<Result<Vec<DynSolValue>>>()?;
+    Ok(function.abi_encode_input(&values)?.into())
+}
+
+/// Turns an [EVMError] into a message that explains the likely cause and how to fix it.
+pub fn explain_evm_error<DBError: Debug>(err: &EVMError<DBError>) -> String {
+    match err {
+        EVMError::Header(InvalidHeader::PrevrandaoNotSet) => {
+            "block env has no prevrandao: the fork block must be post-merge and its header must carry a mix hash".to_string()
+        }
+        EVMError::Header(InvalidHeader::ExcessBlobGasNotSet) => {
+            "block env has no excess blob gas: the spec is Cancun or later but the block header has no `excess_blob_gas`, fork a Cancun block or use an older spec".to_string()
+        }
+        EVMError::Transaction(InvalidTransaction::LackOfFundForMaxFee { fee, balance }) => {
+            format!("caller balance {} can not cover the max fee {}: lower the gas price or deal ether to the caller", balance, fee)
+        }
+        EVMError::Transaction(InvalidTransaction::NonceTooHigh { tx, state }) => {
+            format!("tx nonce {} is higher than the caller nonce {}: the caller account was probably modified before the exploit", tx, state)
+        }
+        EVMError::Transaction(InvalidTransaction::NonceTooLow { tx, state }) => {
+            format!("tx nonce {} is lower than the caller nonce {}: the caller account was probably modified before the exploit", tx, state)
+        }
+        EVMError::Transaction(InvalidTransaction::CallerGasLimitMoreThanBlock) => {
+            "tx gas limit is higher than the block gas limit: lower the gas limit".to_string()
+        }
+        EVMError::Transaction(InvalidTransaction::GasPriceLessThanBasefee) => {
+            "tx gas price is lower than the block base fee: raise the gas price".to_string()
+        }
+        EVMError::Transaction(InvalidTransaction::RejectCallerWithCode) => {
+            "the caller account has code deployed: the exploit must be sent from an EOA".to_string()
+        }
+        EVMError::Transaction(err) => format!("invalid transaction: {:?}", err),
+        EVMError::Database(err) => {
+            format!("failed to load state: {:?}, make sure the rpc is an archive node that has the state of the fork block", err)
+        }
+        EVMError::Custom(msg) => msg.clone(),
+        #[allow(unreachable_patterns)]
+        err => format!("{:?}", err),
+    }
+}
+/// Decodes the revert data of the exploit, naming the custom errors of the poc's abi
+/// before falling back to `Error(string)` and `Panic(uint256)`.
+pub fn decode_revert(output: &[u8], abi: Option<&JsonAbi>) -> String {
+    if let (Some(abi), Some(selector)) = (abi, output.get(..4)) {
+        for error in abi.errors() {
+            if error.selector().as_slice() != selector {
+                continue;
+            }
+            if let Ok(args) = error.abi_decode_input(&output[4..], false) {
+                let args: Vec<String> = args.iter().map(format_sol_value).collect();
+                return format!("{}({})", error.name, args.join(", "));
+            }
+        }
+    }
+    decode_revert_reason(output).unwrap_or_else(|| format!("0x{}", hex::encode(output)))
+}
+
+/// Names the function and arguments of a calldata with the abi, falls back to the selector
+/// and the raw arguments.
+pub fn decode_call(input: &[u8], abi: Option<&JsonAbi>) -> String {
+    let Some(selector) = input.get(..4) else {
+        return format!("fallback(0x{})", hex::encode(input));
+    };
+    if let Some(abi) = abi {
+        for function in abi.functions() {
+            if function.selector().as_slice() != selector {
+                continue;
+            }
+            if let Ok(args) = function.abi_decode_input(&input[4..], false) {
+                let args: Vec<String> = args.iter().map(format_sol_value).collect();
+                return format!("{}({})", function.name, args.join(", "));
+            }
+        }
+    }
+    format!("0x{}(0x{})", hex::encode(selector), hex::encode(&input[4..]))
+}
+
+fn format_sol_value(value: &DynSolValue) -> String {
+    match value {
+        DynSolValue::Bool(b) => b.to_string(),
+        DynSolValue::Int(i, _) => i.to_string(),
+        DynSolValue::Uint(u, _) => u.to_string(),
+        DynSolValue::Address(a) => a.to_string(),
+        DynSolValue::String(s) => format!("{:?}", s),
+        DynSolValue::Bytes(b) => format!("0x{}", hex::encode(b)),
+        DynSolValue::FixedBytes(b, size) => format!("0x{}", hex::encode(&b[..*size])),
+        value => format!("{:?}", value),
+    }
+}
+
+/// The address of the contract `sender` deploys with CREATE at `nonce`.
+pub fn compute_create_address(sender: Address, nonce: u64) -> Address {
+    sender.create(nonce)
+}
+
+/// The address of the contract `sender` deploys with CREATE2.
+pub fn compute_create2_address(sender: Address, salt: B256, init_code_hash: B256) -> Address {
+    sender.create2(salt, init_code_hash)
+}
+
+/// Formats a raw token amount with its decimals and thousands separators, e.g.
+/// `1234567800` with 6 decimals is `1,234.5678`. At least one fraction digit is kept.
+pub fn format_token_amount(amount: I256, decimals: u8) -> String {
+    let digits = amount.unsigned_abs().to_string();
+    let decimals = decimals as usize;
+    let digits = format!("{:0>width$}", digits, width = decimals + 1);
+    let (integer, fraction) = digits.split_at(digits.len() - decimals);
+    let fraction = fraction.trim_end_matches('0');
+    let mut grouped = String::new();
+    for (i, c) in integer.chars().enumerate() {
+        if i > 0 && (integer.len() - i) % 3 == 0 {
+            grouped.push(',');
+        }
+        grouped.push(c);
+    }
+    format!(
+        "{}{}.{}",
+        if amount.is_negative() { "-" } else { "" },
+        grouped,
+        if fraction.is_empty() { "0" } else { fraction }
+    )
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy_primitives::keccak256;
+
+    #[test]
+    fn evm_errors_explain_their_fix() {
+        let err: EVMError<()> = EVMError::Header(InvalidHeader::PrevrandaoNotSet);
+        assert!(explain_evm_error(&err).contains("must be post-merge"));
+
+        let err: EVMError<()> = EVMError::Transaction(InvalidTransaction::LackOfFundForMaxFee {
+            fee: Box::new(U256::from(100)),
+            balance: Box::new(U256::from(1)),
+        });
+        assert_eq!(
+            explain_evm_error(&err),
+            "caller balance 1 can not cover the max fee 100: lower the gas price or deal ether to the caller"
+        );
+
+        let err: EVMError<&str> = EVMError::Database("missing trie node");
+        assert!(explain_evm_error(&err).contains("archive node"));
+
+        let err: EVMError<()> = EVMError::Custom("the poc has no exploit function".to_string());
+        assert_eq!(explain_evm_error(&err), "the poc has no exploit function");
+    }
+
+    fn abi() -> JsonAbi {
+        JsonAbi::parse([
+            "function attack(address target, uint256 amount)",
+            "error NotProfitable(uint256 gain)",
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn reverts_name_the_error_of_the_poc() {
+        let error = abi().errors().next().unwrap().clone();
+        let output = error.abi_encode_input(&[DynSolValue::Uint(U256::from(7), 256)]).unwrap();
+        assert_eq!(decode_revert(&output, Some(&abi())), "NotProfitable(7)");
+
+        let output = alloy_sol_types::SolError::abi_encode(&alloy_sol_types::Revert::from("no profit"));
+        assert_eq!(decode_revert(&output, Some(&abi())), "revert: no profit");
+
+        assert_eq!(decode_revert(&[0xff, 0xfe], None), "0xfffe");
+    }
+
+    #[test]
+    fn calls_name_the_function_of_the_poc() {
+        let target = "0x000000000000000000000000000000000000dEaD";
+        let input = encode_calldata("attack(address,uint256)", &[target.to_string(), "5".to_string()]).unwrap();
+        assert_eq!(decode_call(&input, Some(&abi())), format!("attack({}, 5)", target));
+
+        let selector = hex::encode(&input[..4]);
+        assert_eq!(decode_call(&input[..4], None), format!("0x{}(0x)", selector));
+        assert_eq!(decode_call(&[0x01, 0x02], Some(&abi())), "fallback(0x0102)");
+    }
+
+    #[test]
+    fn calldata_is_encoded_from_the_signature() {
+        let input = encode_calldata("swap(uint256,bytes)", &["1".to_string(), "0xbeef".to_string()]).unwrap();
+        let function = Function::parse("swap(uint256,bytes)").unwrap();
+        assert_eq!(input[..4], function.selector()[..]);
+        assert_eq!(
+            function.abi_decode_input(&input[4..], true).unwrap(),
+            vec![DynSolValue::Uint(U256::from(1), 256), DynSolValue::Bytes(vec![0xbe, 0xef])]
+        );
+
+        let err = encode_calldata("swap(uint256,bytes)", &["1".to_string()]).unwrap_err();
+        assert_eq!(err.to_string(), "`swap(uint256,bytes)` takes 2 arguments, 1 given");
+        assert!(encode_calldata("swap(uint256)", &["-1".to_string()]).is_err());
+    }
+
+    #[test]
+    fn token_amounts_are_grouped_with_their_decimals() {
+        assert_eq!(format_token_amount(I256::try_from(1234567800i64).unwrap(), 6), "1,234.5678");
+        assert_eq!(format_token_amount(I256::try_from(-5_000_000i64).unwrap(), 6), "-5.0");
+        assert_eq!(format_token_amount(I256::ZERO, 18), "0.0");
+        assert_eq!(format_token_amount(I256::try_from(1i64).unwrap(), 18), "0.000000000000000001");
+        assert_eq!(format_token_amount(I256::try_from(1000i64).unwrap(), 0), "1,000.0");
+    }
+
+    #[test]
+    fn create_addresses_match_known_vectors() {
+        let sender: Address = "0x6ac7ea33f8831ea9dcc53393aaa88b25a785dbf0".parse().unwrap();
+        assert_eq!(compute_create_address(sender, 0), "0xcd234a471b72ba2f1ccf0a70fcaba648a5eecd8d".parse::<Address>().unwrap());
+        assert_eq!(compute_create_address(sender, 1), "0x343c43a37d37dff08ae8c4a11544c718abb4fcf8".parse::<Address>().unwrap());
+        assert_eq!(compute_create_address(sender, 2), "0xf778b86fa74e846c4f0a1fbd1335fe81c00a0c91".parse::<Address>().unwrap());
+
+        // the examples of EIP-1014
+        let init_code_hash = keccak256([0x00]);
+        assert_eq!(
+            compute_create2_address(Address::ZERO, B256::ZERO, init_code_hash),
+            "0x4D1A2e2bB4F88F0250f26Ffff098B0b30B26BF38".parse::<Address>().unwrap()
+        );
+        assert_eq!(
+            compute_create2_address("0xdeadbeef00000000000000000000000000000000".parse().unwrap(), B256::ZERO, init_code_hash),
+            "0xB928f69Bb1D91Cd65274e3c79d8986362984fDA3".parse::<Address>().unwrap()
+        );
+    }
+}
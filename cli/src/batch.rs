@@ -0,0 +1,141 @@
+use clap::Parser;
+use anyhow::{bail, Context, Result};
+use std::{collections::BTreeMap, fs, path::PathBuf};
+use alloy_provider::ProviderBuilder;
+use serde::Deserialize;
+
+use crate::chains::evm::EvmArgs;
+use crate::fork::ForkArgs;
+use crate::redact_url;
+
+/// One exploit of a batch
+#[derive(Debug, Deserialize)]
+pub struct BatchJob {
+    pub poc: PathBuf,
+    pub block_number: u64,
+}
+
+#[derive(Parser, Debug)]
+pub struct BatchArgs {
+    /// A json file of the jobs, `[{poc, block_number}]`
+    jobs: PathBuf,
+
+    /// The block of each job overrides `--block-number`
+    #[clap(flatten)]
+    fork: ForkArgs,
+
+    /// The proofs are written to `<output-dir>/proof-<job index>.bin`
+    #[clap(long, default_value = ".")]
+    output_dir: PathBuf,
+
+    /// Just simulate the exploit txs, don't actually generate proofs.
+    #[clap(long)]
+    dry_run: bool,
+
+    /// The flags of `evm` every job runs with, after `--`, e.g. `-- --deal 1ether --trace`
+    #[clap(last = true)]
+    evm_args: Vec<String>,
+}
+
+/// Groups the jobs by block, keeping their index in the job file. The jobs of a group run
+/// over one db.
+pub fn group_jobs(jobs: Vec<BatchJob>) -> BTreeMap<u64, Vec<(usize, BatchJob)>> {
+    let mut blocks: BTreeMap<u64, Vec<(usize, BatchJob)>> = BTreeMap::new();
+    for (index, job) in jobs.into_iter().enumerate() {
+        blocks.entry(job.block_number).or_default().push((index, job));
+    }
+    blocks
+}
+
+impl BatchArgs {
+    /// The `evm` args of a job, the fork is resolved once per block by the batch so the
+    /// `evm` flags may not set it again.
+    fn job_args(&self, index: usize, job: &BatchJob) -> Result<EvmArgs> {
+        let poc = job.poc.to_str().context("the poc path is not utf-8")?;
+        let output = self.output_dir.join(format!("proof-{}.bin", index));
+        let output = output.to_str().context("the output dir is not utf-8")?;
+        let block_number = job.block_number.to_string();
+        let mut argv = vec!["evm", poc, "--block-number", &block_number, "--output", output];
+        if self.dry_run {
+            argv.push("--dry-run");
+        }
+        argv.extend(self.evm_args.iter().map(String::as_str));
+        let args = EvmArgs::try_parse_from(argv)
+            .with_context(|| format!("Invalid evm flags for job {}", index))?;
+        if args.fork != (ForkArgs { block_number: Some(job.block_number), ..Default::default() }) {
+            bail!("The rpc and block flags of a batch go before `--`, not in the evm flags")
+        }
+        Ok(args)
+    }
+
+    /// Executes the `batch` subcommand. Every job runs the `evm` pipeline, the jobs share one
+    /// provider, and the jobs of the same block share one cache so the accounts they all read
+    /// are fetched once.
+    pub async fn run(self) -> Result<()> {
+        if self.fork.timestamp.is_some() || self.fork.header_file.is_some() {
+            bail!("The jobs give their own block, --timestamp and --header-file don't apply to a batch")
+        }
+        let content = fs::read_to_string(&self.jobs)
+            .with_context(|| format!("Failed to read job file {:?}", self.jobs))?;
+        let jobs: Vec<BatchJob> = serde_json::from_str(&content)
+            .with_context(|| format!("Malformed job file {:?}", self.jobs))?;
+        let blocks = group_jobs(jobs);
+
+        let rpc_url = self.fork.rpc_url()?;
+        println!("RPC: {}", redact_url(&rpc_url));
+        let provider = ProviderBuilder::new()
+            .on_http(rpc_url.as_str().try_into()?)?;
+        fs::create_dir_all(&self.output_dir)?;
+
+        for (block_number, jobs) in blocks {
+            let fork_args = ForkArgs { block_number: Some(block_number), ..self.fork.clone() };
+            let fork = fork_args.resolve(&provider).await?;
+            println!("Chain: {:?}", fork.chain_id);
+            let db = fork.open_db(&provider)?;
+
+            for (index, job) in jobs {
+                println!("Job {}: {} at block {}", index, job.poc.display(), block_number);
+                self.job_args(index, &job)?
+                    .run_on(&fork, &db)
+                    .with_context(|| format!("Job {} failed", index))?;
+            }
+            db.flush();
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(poc: &str, block_number: u64) -> BatchJob {
+        BatchJob { poc: PathBuf::from(poc), block_number }
+    }
+
+    fn batch(argv: &[&str]) -> BatchArgs {
+        BatchArgs::try_parse_from(["batch", "jobs.json"].iter().chain(argv)).unwrap()
+    }
+
+    #[test]
+    fn jobs_of_a_block_share_one_db() {
+        let blocks = group_jobs(vec![job("a.sol", 200), job("b.sol", 100), job("c.sol", 200)]);
+        assert_eq!(blocks.len(), 2);
+        let indexes: Vec<usize> = blocks[&200].iter().map(|(index, _)| *index).collect();
+        assert_eq!(indexes, vec![0, 2]);
+        assert_eq!(blocks[&100][0].1.poc, PathBuf::from("b.sol"));
+    }
+
+    #[test]
+    fn jobs_run_with_the_evm_flags_of_the_batch() {
+        let args = batch(&["--dry-run", "--", "--deal", "1ether", "--skip-self-verify"])
+            .job_args(3, &job("a.sol", 100))
+            .unwrap();
+        assert_eq!(args.fork.block_number, Some(100));
+        assert!(args.dry_run);
+
+        assert!(batch(&["--", "--block-number", "5"]).job_args(0, &job("a.sol", 100)).is_err());
+        assert!(batch(&["--", "--rpc-url", "http://localhost:8545"]).job_args(0, &job("a.sol", 100)).is_err());
+    }
+}
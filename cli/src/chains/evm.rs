@@ -1,113 +1,362 @@
 use clap::Parser;
 use clio::OutputPath;
-use anyhow::Result;
-use alloy_provider::{Provider, ProviderBuilder};
-use alloy_rpc_types::BlockId;
-use alloy_primitives::U256;
+use anyhow::{Context, Result};
+use log::info;
+use alloy_provider::{Network, Provider, ProviderBuilder};
+use alloy_transport::Transport;
 use chains_evm_core::{
-    block::BlockHeader, db::{BlockchainDbMeta, ChainSpec, JsonBlockCacheDB}, 
-    deal::DealRecord, poc_compiler::compile_poc, preflight::build_input
+    inspector::format_call_tree, db::{ChainSpec, JsonBlockCacheDB},
+    poc_compiler::{compile_poc, EVM_VERSION, SOLC_VERSION}, preflight::{build_input, check_profit, check_reproducible, check_state_entries, PreflightConfig}
 };
 use risc0_zkvm::{ExecutorEnv, ExecutorImpl};
-use guests::{EXPLOIT_ID, EXPLOIT_ELF};
-use std::time::Instant;
+use guests::EXPLOIT_ELF;
+use std::time::{Duration, Instant};
 
-use crate::proof::Proof;
+#[cfg(feature = "metrics")]
+use crate::metrics::Metrics;
+use crate::proof::{Proof, EXPLOIT_IMAGE_ID};
+use crate::tools::{dump_memdb, save_input};
+use crate::exploit::ExploitArgs;
+use crate::fork::{Fork, ForkArgs};
+use crate::{redact_url, watchdog};
 
 #[derive(Parser, Debug)]
 pub struct EvmArgs {
     /// The poc contract
     poc: String,
 
-    #[clap(short, long)]
-    rpc_url: String,
+    #[clap(flatten)]
+    pub fork: ForkArgs,
 
-    #[clap(short, long)]
-    block_number: Option<u64>,
-    /// Set the token balances of the poc contract.
-    /// Examples: 1ether, 0xdac17f958d2ee523a2206206994597c13d831ec7:10gwei
-    #[clap(short, long)]
-    deal: Option<Vec<DealRecord>>,
+    #[clap(flatten)]
+    exploit: ExploitArgs,
+
+    /// Fail if the committed state has more entries than this
+    #[clap(long)]
+    max_state_entries: Option<usize>,
+    /// Fail before proving if the poc contract gains nothing in any token
+    #[clap(long)]
+    fail_on_no_profit: bool,
+    /// Print the gas the exploit spent on storage, calls and computation
+    #[clap(long)]
+    gas_breakdown: bool,
+    /// When the exploit reverts, print its calls up to the revert along with the reason
+    #[clap(long)]
+    continue_on_revert: bool,
+    /// Print the calls of the exploit as a tree
+    #[clap(long)]
+    trace: bool,
+    /// Build the input twice and fail unless both are the same bytes
+    #[clap(long)]
+    check_reproducible: bool,
     /// Just simulate the exploit tx, don't actually generate a proof.
     #[clap(long)]
     pub dry_run: bool,
+    /// Only run the preflight to populate the rpc cache of the block, then exit.
+    #[clap(long, conflicts_with = "dry_run")]
+    pub save_cache_only: bool,
 
     /// Output file
     #[clap(long, short, value_parser, default_value = "proof.bin")]
     output: OutputPath,
+
+    /// Also write the guest input to this file before proving, like `pre` does
+    #[clap(long, value_parser)]
+    keep_input: Option<OutputPath>,
+
+    /// Write the committed accounts, slots and block hashes as json
+    #[clap(long, value_parser)]
+    dump_memdb: Option<OutputPath>,
+
+    /// Don't verify the receipt right after proving it
+    #[clap(long)]
+    skip_self_verify: bool,
+
+    /// Fail instead of writing a proof larger than this, in bytes
+    #[clap(long)]
+    max_proof_size: Option<u64>,
+
+    /// Timeout of proving, in seconds
+    #[clap(long)]
+    prove_timeout: Option<u64>,
+
+    /// Write the metrics of the run to this file in the Prometheus text format
+    #[cfg(feature = "metrics")]
+    #[clap(long)]
+    metrics_file: Option<std::path::PathBuf>,
+
+    /// Serve the metrics of the run over http on this address while it runs
+    #[cfg(feature = "metrics")]
+    #[clap(long)]
+    metrics_addr: Option<std::net::SocketAddr>,
 }
 
 impl EvmArgs {
     /// Executes the `evm` subcommand.
     pub async fn run(self) -> Result<()> {
-        let contract = compile_poc(self.poc)?;
-        let poc_code_hash = contract.hash_slow();
-
-        let provider = ProviderBuilder::new()
-            .on_http(self.rpc_url.as_str().try_into()?)?;
-
-        let block_id = match self.block_number {
-            Some(n) => BlockId::number(n),
-            None => BlockId::safe()
+        let rpc_url = self.fork.rpc_url()?;
+        println!("RPC: {}", redact_url(&rpc_url));
+        let provider;
+        let (fork, db) = match rpc_url.strip_prefix("file://") {
+            // a snapshot exported by `snapshot` serves every read, no node is needed
+            Some(path) => {
+                let db = JsonBlockCacheDB::from_snapshot(path)?;
+                (self.fork.snapshot_fork(&db.data().meta)?, db)
+            }
+            None => {
+                provider = ProviderBuilder::new()
+                    .on_http(rpc_url.as_str().try_into()?)?;
+                let fork = self.fork.resolve(&provider).await?;
+                let db = fork.open_db(&provider)?;
+                (fork, db)
+            }
         };
-        let chain_id = provider.get_chain_id().await?;
-        let block = provider.get_block(block_id, false).await?.expect("could not found block");
-        let block_number = block.header.number.unwrap();
-        println!("Chain: {:?}", chain_id);
-        println!("Block Number: {:?}", block_number);
+        println!("Chain: {:?}", fork.chain_id);
+        println!("Block Number: {:?}", fork.header.number);
+        if fork.env_header.number != fork.header.number {
+            println!("Env Block Number: {:?}", fork.env_header.number);
+        }
+        self.run_on(&fork, &db)
+    }
+
+    /// Proves the poc on a resolved fork, reading its state through `db`. The fork flags of
+    /// the args are not read again, `batch` runs the jobs of a block over one db this way so
+    /// the accounts they share are fetched once.
+    pub fn run_on<T, N, P>(self, fork: &Fork, db: &JsonBlockCacheDB<T, N, P>) -> Result<()>
+    where
+        T: Transport + Clone, N: Network, P: Provider<T, N> + Clone,
+    {
+        let phase = Instant::now();
+        let poc = compile_poc(self.poc)?;
+        let poc_code_hash = poc.bytecode.hash_slow();
+        info!("phase=compile elapsed={:?} poc_code_hash={}", phase.elapsed(), poc_code_hash);
         println!("Poc Code Hash: {:?}", poc_code_hash);
-        let rpc_cache_dir = dirs_next::home_dir().expect("home dir not found").join(".securfi").join("cache").join("rpc");
-        let cache_path =  rpc_cache_dir.join(format!("{}", chain_id)).join(format!("{}.json", block.header.number.unwrap()));
 
-        let header: BlockHeader = block.header.try_into()?;
+        let (chain_id, block_number, env_header) = (fork.chain_id, fork.header.number, fork.env_header.clone());
+        let cache_path = fork.cache_path();
 
-        let chain_spec = ChainSpec::mainnet();
-        let meta = BlockchainDbMeta {
-            chain_spec: chain_spec.clone(), // currently only supports mainnet and shanghai
-            header: header.clone(),
+        let config = PreflightConfig {
+            gas_breakdown: self.gas_breakdown,
+            trace_revert: self.continue_on_revert,
+            trace: self.trace,
+            ..self.exploit.config(chain_id, &poc)?
         };
-        let db = JsonBlockCacheDB::new(&provider, meta, Some(cache_path));
+        #[cfg(feature = "metrics")]
+        let metrics = Metrics::default();
+        #[cfg(feature = "metrics")]
+        if let Some(addr) = self.metrics_addr {
+            println!("Serving metrics on http://{}", metrics.serve(addr)?);
+        }
+        let phase = Instant::now();
+        let (exploit_input, report) = build_input(poc.bytecode.clone(), env_header.clone(), db, &config)?;
+        #[cfg(feature = "metrics")]
+        {
+            let (cache_hits, rpc_requests) = db.read_counts();
+            metrics.duration("preflight_seconds", "Duration of the preflight", phase.elapsed());
+            metrics.gauge("rpc_requests", "Reads of the preflight that went to the rpc", rpc_requests as f64);
+            metrics.gauge("cache_hits", "Reads of the preflight served from the rpc cache", cache_hits as f64);
+        }
+        info!(
+            "phase=preflight elapsed={:?} chain={} block={} gas={} state_entries={}",
+            phase.elapsed(), chain_id, block_number, report.gas_used, exploit_input.db.state_entries()
+        );
+        if self.check_reproducible {
+            check_reproducible(&exploit_input, poc.bytecode, env_header.clone(), db, &config)?;
+        }
+        for warning in report.warnings() {
+            println!("Warning: {}", warning);
+        }
+        if self.trace {
+            println!("{}", format_call_tree(&report.calls, config.poc_abi.as_ref()));
+        }
+        if let Some(breakdown) = report.gas_breakdown {
+            println!(
+                "Gas breakdown: storage {}, calls {}, computation {}",
+                breakdown.storage, breakdown.calls, breakdown.computation
+            );
+        }
+        if let Some(max_state_entries) = self.max_state_entries {
+            check_state_entries(&exploit_input, max_state_entries)?;
+        }
+        if self.fail_on_no_profit {
+            check_profit(&exploit_input, &ChainSpec::new(chain_id))?;
+        }
+        if self.save_cache_only {
+            db.flush();
+            println!("Cache saved: {}", cache_path.display());
+            return Ok(());
+        }
 
-        // todo: add deal
-        let initial_balance = U256::ZERO;
-        let exploit_input = build_input(contract, header, &db, initial_balance)?;
+        if let Some(path) = self.keep_input {
+            save_input(&exploit_input, path.create()?)?;
+        }
+        if let Some(path) = self.dump_memdb {
+            dump_memdb(&exploit_input.db, path.create()?)?;
+        }
 
         let zk_env = ExecutorEnv::builder()
             .write(&exploit_input)?
             .build()?;
         
+        let phase = Instant::now();
         let mut exec = ExecutorImpl::from_elf(zk_env, EXPLOIT_ELF)?;
         let session = exec.run()?;
-        let evm_id: Vec<u8> = EXPLOIT_ID.iter().flat_map(|x| x.to_le_bytes()).collect();
+        info!("phase=execute elapsed={:?} segments={}", phase.elapsed(), session.segments.len());
+        #[cfg(feature = "metrics")]
+        {
+            metrics.duration("execute_seconds", "Duration of the guest execution", phase.elapsed());
+            metrics.gauge("user_cycles", "Cycles of the guest execution", session.user_cycles as f64);
+            metrics.gauge("segments", "Segments of the guest execution", session.segments.len() as f64);
+        }
 
         
         if !self.dry_run {
             println!(
                 "starting generate zk proof, image id: {}",
-                hex::encode(evm_id)
+                EXPLOIT_IMAGE_ID.to_hex()
             );
+            // all the rpc work is done, keep it even if proving times out
+            db.flush();
             let start = Instant::now();
+            let prove_guard = self.prove_timeout.map(|secs| watchdog("proving", Duration::from_secs(secs)));
             let receipt = session.prove()?.receipt;
-            let _ = receipt.verify(EXPLOIT_ID);
+            drop(prove_guard);
+            if !self.skip_self_verify {
+                receipt.verify(*EXPLOIT_IMAGE_ID.as_words()).context("The new receipt doesn't verify")?;
+            }
             let duration = start.elapsed();
+            info!("phase=prove elapsed={:?}", duration);
+            #[cfg(feature = "metrics")]
+            metrics.duration("prove_seconds", "Duration of proving", duration);
 
-            let spec_name: &'static str = chain_spec.spec_id.into();
-            let image_id = hex::encode(EXPLOIT_ID.iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<u8>>());
+            let spec_name: &'static str = exploit_input.spec_id.into();
             let proof = Proof {
                 version: env!("CARGO_PKG_VERSION").to_string(),
-                image_id: image_id,
+                image_id: EXPLOIT_IMAGE_ID.to_hex(),
                 chain_id: chain_id,
                 spec_id: spec_name.to_string(),
                 block_number: block_number,
+                env_block_number: env_header.number,
                 poc_code_hash: poc_code_hash,
-                deals: self.deal.unwrap_or_default(),
+                deals: config.deals.clone(),
+                initial_balance: config.initial_balance,
+                solc_version: SOLC_VERSION.to_string(),
+                evm_version: EVM_VERSION.to_string(),
                 receipt: Some(receipt),
             };
+            if let Some(max_proof_size) = self.max_proof_size {
+                proof.check_size(max_proof_size)?;
+            }
             let output = self.output.create()?;
             proof.save(output)?;
-            println!("generate zk proof success, time: {:?}", duration);
+            println!("generate zk proof success, time: {:?}, size: {} bytes", duration, proof.size()?);
+        }
+        #[cfg(feature = "metrics")]
+        if let Some(path) = &self.metrics_file {
+            metrics.write(std::fs::File::create(path)?)?;
         }
         Ok(())
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy_primitives::{Address, U256};
+    use bridge::{DEFAULT_CALLER, DEFAULT_CONTRACT_ADDRESS};
+    use chains_evm_core::{block::BlockHeader, db::{BlockchainDbMeta, JsonBlockCacheData}};
+    use revm_primitives::AccountInfo;
+    use std::{fs, path::{Path, PathBuf}};
+    use crate::tools::PreArgs;
+
+    fn fork() -> Fork {
+        let header = BlockHeader {
+            number: 18_000_000,
+            timestamp: 1_700_000_000,
+            gas_limit: 30_000_000,
+            base_fee_per_gas: U256::from(7),
+            author: Address::repeat_byte(0xc0),
+            ..Default::default()
+        };
+        Fork { chain_id: 1, header: header.clone(), env_header: header, rpc_timeout: None, skip_archive_check: true }
+    }
+
+    /// A temp dir holding a poc doing nothing and, in `cache.json`, the rpc cache of every
+    /// account it reads
+    fn workdir(name: &str, fork: &Fork) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("zkprover-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("Exploit.sol"), "pragma solidity ^0.8.0;\ncontract Exploit { function exploit() external {} }\n").unwrap();
+        let mut cache = JsonBlockCacheData {
+            meta: BlockchainDbMeta::new(ChainSpec::new(fork.chain_id), fork.header.clone()),
+            accounts: Default::default(),
+            storage: Default::default(),
+            block_hashes: Default::default(),
+        };
+        for address in [DEFAULT_CONTRACT_ADDRESS, DEFAULT_CALLER, fork.header.author] {
+            cache.accounts.insert(address, AccountInfo::default());
+        }
+        fs::write(dir.join("cache.json"), serde_json::to_string(&cache).unwrap()).unwrap();
+        dir
+    }
+
+    /// Serves the reads from the cache `db` saved to `path` only, a db of the same type
+    fn reopen<T, N, P>(_db: &JsonBlockCacheDB<T, N, P>, path: &Path) -> JsonBlockCacheDB<T, N, P>
+    where
+        T: Transport + Clone, N: Network, P: Provider<T, N>,
+    {
+        JsonBlockCacheDB::from_snapshot(path).unwrap()
+    }
+
+    #[test]
+    fn save_cache_only_writes_a_complete_cache() {
+        let fork = fork();
+        let dir = workdir("save-cache-only", &fork);
+        let (poc, path) = (dir.join("Exploit.sol"), dir.join("cache.json"));
+        // nothing listens on the rpc, a read the cache misses fails
+        let provider = ProviderBuilder::new().on_http("http://127.0.0.1:1".parse().unwrap()).unwrap();
+        let meta = BlockchainDbMeta::new(ChainSpec::new(fork.chain_id), fork.header.clone());
+        let db = JsonBlockCacheDB::new(provider, meta, Some(path.clone()));
+        fs::remove_file(&path).unwrap();
+
+        let args = EvmArgs::try_parse_from(["evm", poc.to_str().unwrap(), "--save-cache-only"]).unwrap();
+        args.run_on(&fork, &db).unwrap();
+        assert!(path.exists());
+
+        // the saved cache alone serves every read of the preflight
+        let saved = reopen(&db, &path);
+        assert_eq!(saved.data().accounts, db.data().accounts);
+        let poc = compile_poc(poc).unwrap();
+        let config = PreflightConfig { chain_id: fork.chain_id, ..Default::default() };
+        let (input, _) = build_input(poc.bytecode.clone(), fork.env_header.clone(), &db, &config).unwrap();
+        let (saved_input, _) = build_input(poc.bytecode, fork.env_header.clone(), &saved, &config).unwrap();
+        assert_eq!(saved_input, input);
+
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn kept_input_is_the_input_of_pre() {
+        let dir = workdir("keep-input", &fork());
+        let poc = dir.join("Exploit.sol").to_str().unwrap().to_string();
+        let rpc_url = format!("file://{}", dir.join("cache.json").display());
+        let (kept, pre_input) = (dir.join("kept.bin"), dir.join("input.bin"));
+
+        let args = EvmArgs::try_parse_from([
+            "evm", poc.as_str(), "--rpc-url", rpc_url.as_str(), "--dry-run", "--keep-input", kept.to_str().unwrap(),
+        ]).unwrap();
+        args.run().await.unwrap();
+        let args = PreArgs::try_parse_from([
+            "pre", poc.as_str(), "--rpc-url", rpc_url.as_str(),
+            "--output", pre_input.to_str().unwrap(), "--proof", dir.join("sketch_proof.bin").to_str().unwrap(),
+        ]).unwrap();
+        args.run().await.unwrap();
+
+        let kept = fs::read(&kept).unwrap();
+        assert!(!kept.is_empty());
+        assert_eq!(kept, fs::read(&pre_input).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
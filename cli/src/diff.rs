@@ -0,0 +1,150 @@
+use std::collections::BTreeSet;
+use clap::Parser;
+use clio::{Input, Output};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use alloy_primitives::{Address, B256};
+use chains_evm_core::{
+    balance_change::AssetChange,
+    state_diff::{AccountDiff, ChangedType, Delta},
+    summary::summarize_exploit,
+};
+use crate::proof::Proof;
+
+
+#[derive(Parser, Debug)]
+pub struct DiffArgs {
+    /// the old proof file
+    old: Input,
+
+    /// the new proof file
+    new: Input,
+
+    /// Output file
+    #[clap(long, short, value_parser, default_value = "-")]
+    output: Output,
+}
+
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ProofDiff {
+    pub poc_code_hash: Delta<B256>,
+    pub gas_used: Delta<u64>,
+    pub gas_refunded: Delta<u64>,
+    /// The accounts whose state diff differs between the proofs
+    pub state_diff: Vec<(Address, Delta<AccountDiff>)>,
+    pub asset_change: Delta<Vec<AssetChange>>,
+}
+
+fn delta<T: PartialEq>(old: T, new: T) -> Delta<T> {
+    if old == new {
+        Delta::Unchanged
+    } else {
+        Delta::Changed(ChangedType { from: old, to: new })
+    }
+}
+
+/// Compares the committed outputs of two proofs.
+pub fn diff_proofs(old: &Proof, new: &Proof) -> Result<ProofDiff> {
+    let old_output = old.output()?;
+    let new_output = new.output()?;
+
+    let old_summary = summarize_exploit(&old_output, None)?;
+    let new_summary = summarize_exploit(&new_output, None)?;
+    let (old_state_diff, new_state_diff) = (&old_summary.state_diff, &new_summary.state_diff);
+    let addresses: BTreeSet<Address> = old_state_diff.keys().chain(new_state_diff.keys()).cloned().collect();
+    let mut state_diff = Vec::new();
+    for address in addresses {
+        let account_delta = match (old_state_diff.get(&address), new_state_diff.get(&address)) {
+            (Some(old), Some(new)) => delta(old.clone(), new.clone()),
+            (Some(old), None) => Delta::Removed(old.clone()),
+            (None, Some(new)) => Delta::Added(new.clone()),
+            (None, None) => Delta::Unchanged,
+        };
+        if account_delta != Delta::Unchanged {
+            state_diff.push((address, account_delta));
+        }
+    }
+
+    Ok(ProofDiff {
+        poc_code_hash: delta(old.poc_code_hash, new.poc_code_hash),
+        gas_used: delta(old_summary.gas_used, new_summary.gas_used),
+        gas_refunded: delta(old_summary.gas_refunded, new_summary.gas_refunded),
+        state_diff: state_diff,
+        asset_change: delta(old_summary.asset_change, new_summary.asset_change),
+    })
+}
+
+
+impl DiffArgs {
+    pub fn run(self) -> Result<()> {
+        let old = Proof::load(self.old)?;
+        let new = Proof::load(self.new)?;
+        let diff = diff_proofs(&old, &new)?;
+
+        serde_json::to_writer(self.output, &diff)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy_primitives::{Bytes, U256};
+    use bridge::{ExploitInput, ExploitOutput, MemDB};
+    use guests::EXPLOIT_ID;
+    use revm_primitives::{BlockEnv, SpecId};
+    use risc0_zkvm::{FakeReceipt, InnerReceipt, Receipt, ReceiptClaim};
+
+    /// A proof whose fake receipt commits a no-op exploit spending `gas_used`
+    fn proof(gas_used: u64) -> Proof {
+        let output = ExploitOutput {
+            input: ExploitInput {
+                db: MemDB::default(),
+                block_env: BlockEnv::default(),
+                spec_id: SpecId::SHANGHAI,
+                chain_id: 1,
+                gas_price: U256::from(7),
+                gas_limit: 30_000_000,
+                blob_hashes: Vec::new(),
+                max_fee_per_blob_gas: None,
+                setup_txs: Vec::new(),
+                expect_revert: false,
+                calldata: Bytes::new(),
+            },
+            gas_used,
+            gas_refunded: 0,
+            logs: Vec::new(),
+            state: Default::default(),
+            revert: None,
+        };
+        let journal: Vec<u8> = bytemuck::cast_slice(&risc0_zkvm::serde::to_vec(&output).unwrap()).to_vec();
+        let claim = ReceiptClaim::ok(EXPLOIT_ID, journal.clone());
+        Proof {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            image_id: crate::proof::EXPLOIT_IMAGE_ID.to_hex(),
+            chain_id: 1,
+            spec_id: "SHANGHAI".to_string(),
+            block_number: 19_000_000,
+            env_block_number: 19_000_000,
+            poc_code_hash: B256::repeat_byte(1),
+            deals: Vec::new(),
+            initial_balance: U256::ZERO,
+            solc_version: "0.8.20".to_string(),
+            evm_version: "shanghai".to_string(),
+            receipt: Some(Receipt::new(InnerReceipt::Fake(FakeReceipt::new(claim)), journal)),
+        }
+    }
+
+    #[test]
+    fn diff_reports_the_gas_delta() {
+        let diff = diff_proofs(&proof(21_000), &proof(35_000)).unwrap();
+        assert_eq!(diff.gas_used, Delta::Changed(ChangedType { from: 21_000, to: 35_000 }));
+        assert_eq!(diff.gas_refunded, Delta::Unchanged);
+        assert_eq!(diff.poc_code_hash, Delta::Unchanged);
+        assert!(diff.state_diff.is_empty());
+
+        let diff = diff_proofs(&proof(21_000), &proof(21_000)).unwrap();
+        assert_eq!(diff.gas_used, Delta::Unchanged);
+    }
+}
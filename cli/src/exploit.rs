@@ -0,0 +1,121 @@
+use clap::Parser;
+use anyhow::Result;
+use std::path::PathBuf;
+use alloy_primitives::{Address, B256, U256};
+use bridge::DEFAULT_CONTRACT_ADDRESS;
+use chains_evm_core::{
+    deal::{ether_balance, load_deals, DealRecord},
+    poc_compiler::CompiledPoc,
+    preflight::{load_setup_txs, PreflightConfig},
+    utils::encode_calldata,
+};
+
+/// The exploit tx and the state it starts from, shared by the subcommands that run a
+/// preflight so they all build the same input.
+#[derive(Parser, Debug, Clone, Default)]
+pub struct ExploitArgs {
+    /// Set the ether or erc20 balances of the poc contract or other accounts.
+    /// Examples: 1ether, 1ether:for:<address>, <token>:1000000
+    #[clap(short, long)]
+    pub deal: Option<Vec<DealRecord>>,
+    /// Load more deals from a json or toml file, merged with `--deal`.
+    #[clap(long)]
+    pub deal_file: Option<PathBuf>,
+    /// The gas limit of the exploit tx, defaults to the block gas limit
+    #[clap(short, long)]
+    pub gas: Option<u64>,
+    /// Blob versioned hashes of the exploit tx, requires a Cancun block
+    #[clap(long = "blob-hash")]
+    pub blob_hashes: Vec<B256>,
+    /// Max fee per blob gas of the exploit tx, in wei
+    #[clap(long)]
+    pub max_fee_per_blob_gas: Option<U256>,
+    /// Run the exploit with this block coinbase instead of the block's miner
+    #[clap(long)]
+    pub coinbase: Option<Address>,
+    /// Apply the txs of this json file, `[{from, to, data, value}]`, before the exploit
+    #[clap(long)]
+    pub setup_txs: Option<PathBuf>,
+    /// The gas price of the exploit tx in wei, defaults to the base fee of the block
+    #[clap(long, conflicts_with = "priority_fee")]
+    pub gas_price: Option<U256>,
+    /// The priority fee of the exploit tx in wei, paid to the coinbase on top of the base fee
+    #[clap(long)]
+    pub priority_fee: Option<U256>,
+    /// Prove that the exploit reverts, e.g. that an invariant holds, instead of succeeding
+    #[clap(long)]
+    pub expect_revert: bool,
+    /// Call this function of the poc contract instead of `exploit()`, e.g. `attack(address,uint256)`
+    #[clap(long)]
+    pub entrypoint: Option<String>,
+    /// An argument of the entrypoint, in order
+    #[clap(long = "arg", requires = "entrypoint")]
+    pub args: Vec<String>,
+}
+
+impl ExploitArgs {
+    /// The preflight config of the exploit of `poc`, the options of the report are left
+    /// to the subcommand.
+    pub fn config(&self, chain_id: u64, poc: &CompiledPoc) -> Result<PreflightConfig> {
+        let mut deals = self.deal.clone().unwrap_or_default();
+        if let Some(path) = &self.deal_file {
+            deals.extend(load_deals(path)?);
+        }
+        let initial_balance = ether_balance(&deals, DEFAULT_CONTRACT_ADDRESS).unwrap_or_default();
+        Ok(PreflightConfig {
+            chain_id: chain_id,
+            initial_balance: initial_balance,
+            gas_limit: self.gas,
+            blob_hashes: self.blob_hashes.clone(),
+            max_fee_per_blob_gas: self.max_fee_per_blob_gas,
+            coinbase: self.coinbase,
+            poc_abi: poc.abi.clone(),
+            setup_txs: match &self.setup_txs {
+                Some(path) => load_setup_txs(path)?,
+                None => Vec::new(),
+            },
+            gas_price: self.gas_price,
+            priority_fee: self.priority_fee,
+            expect_revert: self.expect_revert,
+            calldata: match &self.entrypoint {
+                Some(signature) => Some(encode_calldata(signature, &self.args)?),
+                None => None,
+            },
+            deals: deals,
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use revm_primitives::Bytecode;
+
+    #[derive(Parser, Debug)]
+    struct Command {
+        #[clap(flatten)]
+        exploit: ExploitArgs,
+    }
+
+    #[test]
+    fn every_preflight_flag_reaches_the_config() {
+        let command = Command::try_parse_from([
+            "zkProver", "--deal", "2ether", "--gas", "500000", "--gas-price", "7", "--expect-revert",
+            "--coinbase", "0x000000000000000000000000000000000000c0de",
+            "--entrypoint", "attack(uint256)", "--arg", "5",
+        ]).unwrap();
+        let poc = CompiledPoc { bytecode: Bytecode::default(), abi: None };
+        let config = command.exploit.config(1, &poc).unwrap();
+        assert_eq!(config.chain_id, 1);
+        assert_eq!(config.initial_balance, U256::from(2_000_000_000_000_000_000u128));
+        assert_eq!(config.gas_limit, Some(500_000));
+        assert_eq!(config.gas_price, Some(U256::from(7)));
+        assert!(config.expect_revert);
+        assert_eq!(config.coinbase, Some("0x000000000000000000000000000000000000c0de".parse().unwrap()));
+        assert_eq!(config.calldata, Some(encode_calldata("attack(uint256)", &["5".to_string()]).unwrap()));
+        assert_eq!(config.deals.len(), 1);
+
+        assert!(Command::try_parse_from(["zkProver", "--gas-price", "7", "--priority-fee", "1"]).is_err());
+    }
+}
@@ -0,0 +1,223 @@
+use clap::Parser;
+use anyhow::{bail, Context, Result};
+use std::{path::PathBuf, time::Duration};
+use alloy_provider::{Network, Provider};
+use alloy_rpc_types::BlockId;
+use alloy_transport::Transport;
+use chains_evm_core::{
+    block::{block_at_timestamp, load_header, with_timeout, BlockHeader},
+    db::{BlockchainDbMeta, ChainSpec, JsonBlockCacheDB},
+    preflight::check_archive_state,
+};
+
+use crate::verify::env_block_in_window;
+
+/// The rpc and the block the exploit forks from, shared by the subcommands that run a
+/// preflight.
+#[derive(Parser, Debug, Clone, Default, PartialEq)]
+pub struct ForkArgs {
+    /// The rpc url, or `file://<path>` of a snapshot exported by `snapshot` to run without a
+    /// node. Defaults to the `SECURFI_RPC_URL` or `ETH_RPC_URL` environment variable
+    #[clap(short, long)]
+    pub rpc_url: Option<String>,
+    /// The block whose state the exploit reads
+    #[clap(short, long)]
+    pub block_number: Option<u64>,
+    /// Use the last block mined at or before this unix timestamp as `--block-number`
+    #[clap(long, conflicts_with = "block_number")]
+    pub timestamp: Option<u64>,
+    /// Read the header of the state block from this json of `eth_getBlockByNumber` instead
+    /// of the rpc. With a warm rpc cache the preflight then only asks the rpc for the chain id
+    #[clap(long, conflicts_with_all = ["block_number", "timestamp"])]
+    pub header_file: Option<PathBuf>,
+    /// Run the exploit in the env (number, timestamp, basefee...) of this later block, while
+    /// still reading the state of `--block-number`
+    #[clap(long)]
+    pub env_block: Option<u64>,
+    /// Don't probe whether the rpc still has the state of the block
+    #[clap(long)]
+    pub skip_archive_check: bool,
+    /// Timeout of every rpc request, in seconds
+    #[clap(long)]
+    pub rpc_timeout: Option<u64>,
+}
+
+/// A fork resolved from [ForkArgs]
+#[derive(Debug, Clone)]
+pub struct Fork {
+    pub chain_id: u64,
+    /// The block whose state the exploit reads
+    pub header: BlockHeader,
+    /// The block whose env the exploit runs in, the state block unless `--env-block`
+    pub env_header: BlockHeader,
+    pub rpc_timeout: Option<Duration>,
+    pub skip_archive_check: bool,
+}
+
+/// The directory of the rpc caches of a chain
+pub fn rpc_cache_dir(chain_id: u64) -> PathBuf {
+    dirs_next::home_dir().expect("home dir not found").join(".securfi").join("cache").join("rpc").join(format!("{}", chain_id))
+}
+
+impl ForkArgs {
+    pub fn rpc_url(&self) -> Result<String> {
+        crate::rpc_url(self.rpc_url.clone())
+    }
+
+    /// Resolves the state block, from `--header-file`, `--timestamp`, `--block-number` or
+    /// else the safe block, and the env block.
+    pub async fn resolve<T, N, P>(&self, provider: &P) -> Result<Fork>
+    where
+        T: Transport + Clone, N: Network, P: Provider<T, N>,
+    {
+        let rpc_timeout = self.rpc_timeout.map(Duration::from_secs);
+        let chain_id = with_timeout("rpc request", rpc_timeout, provider.get_chain_id()).await??;
+        let header = match &self.header_file {
+            Some(path) => load_header(path)?,
+            None => {
+                let block_number = match self.timestamp {
+                    Some(timestamp) => {
+                        let cache_path = rpc_cache_dir(chain_id).join("timestamps.json");
+                        Some(block_at_timestamp(provider, timestamp, Some(&cache_path), rpc_timeout).await?)
+                    }
+                    None => self.block_number,
+                };
+                let block_id = match block_number {
+                    Some(n) => BlockId::number(n),
+                    None => BlockId::safe()
+                };
+                fetch_header(provider, block_id, rpc_timeout).await?
+            }
+        };
+        let env_header = match self.env_block {
+            Some(env_block) if env_block != header.number => {
+                if !env_block_in_window(header.number, env_block) {
+                    bail!("The env block {} must be within 256 blocks after the state block {}", env_block, header.number)
+                }
+                fetch_header(provider, BlockId::number(env_block), rpc_timeout).await?
+            }
+            _ => header.clone(),
+        };
+        Ok(Fork { chain_id, header, env_header, rpc_timeout, skip_archive_check: self.skip_archive_check })
+    }
+
+    /// The fork of a snapshot exported by `snapshot`, given as a `file://` rpc url. The
+    /// snapshot holds the state of one block and the exploit runs in its env.
+    pub fn snapshot_fork(&self, meta: &BlockchainDbMeta) -> Result<Fork> {
+        let number = meta.header.number;
+        if self.timestamp.is_some() || self.header_file.is_some() {
+            bail!("The snapshot gives the block, --timestamp and --header-file don't apply to it")
+        }
+        for block in [self.block_number, self.env_block].into_iter().flatten() {
+            if block != number {
+                bail!("The snapshot only has block {}, not {}", number, block)
+            }
+        }
+        Ok(Fork {
+            chain_id: meta.chain_spec.chain_id,
+            header: meta.header.clone(),
+            env_header: meta.header.clone(),
+            rpc_timeout: None,
+            skip_archive_check: true,
+        })
+    }
+}
+
+impl Fork {
+    /// The rpc cache of the state block
+    pub fn cache_path(&self) -> PathBuf {
+        rpc_cache_dir(self.chain_id).join(format!("{}.json", self.header.number))
+    }
+
+    /// Opens the rpc cache of the state block, then checks the rpc still has its state
+    /// unless `--skip-archive-check`.
+    pub fn open_db<T, N, P>(&self, provider: P) -> Result<JsonBlockCacheDB<T, N, P>>
+    where
+        T: Transport + Clone, N: Network, P: Provider<T, N>,
+    {
+        let meta = BlockchainDbMeta::new(ChainSpec::new(self.chain_id), self.header.clone());
+        let mut db = JsonBlockCacheDB::new(provider, meta, Some(self.cache_path()));
+        db.set_timeout(self.rpc_timeout);
+        if !self.skip_archive_check {
+            check_archive_state(&db, &ChainSpec::new(self.chain_id), &self.header)?;
+        }
+        Ok(db)
+    }
+}
+
+async fn fetch_header<T, N, P>(provider: &P, block_id: BlockId, timeout: Option<Duration>) -> Result<BlockHeader>
+where
+    T: Transport + Clone, N: Network, P: Provider<T, N>,
+{
+    with_timeout("rpc request", timeout, provider.get_block(block_id, false))
+        .await??
+        .with_context(|| format!("could not find block {:?}", block_id))?
+        .header
+        .try_into()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy_primitives::{Address, B256, U256};
+
+    #[derive(Parser, Debug)]
+    struct Command {
+        #[clap(flatten)]
+        fork: ForkArgs,
+    }
+
+    #[test]
+    fn the_state_block_is_chosen_one_way() {
+        let command = Command::try_parse_from(["zkProver", "-b", "100", "--env-block", "101", "--rpc-timeout", "5"]).unwrap();
+        assert_eq!(command.fork.block_number, Some(100));
+        assert_eq!(command.fork.env_block, Some(101));
+        assert_eq!(command.fork.rpc_timeout, Some(5));
+
+        assert!(Command::try_parse_from(["zkProver", "-b", "100", "--timestamp", "1700000000"]).is_err());
+        assert!(Command::try_parse_from(["zkProver", "--timestamp", "1700000000", "--header-file", "header.json"]).is_err());
+    }
+
+    fn header() -> BlockHeader {
+        BlockHeader {
+            parent_hash: B256::ZERO,
+            uncles_hash: B256::ZERO,
+            author: Address::ZERO,
+            state_root: B256::ZERO,
+            transactions_root: B256::ZERO,
+            receipts_root: B256::ZERO,
+            logs_bloom: Default::default(),
+            difficulty: U256::ZERO,
+            number: 19_000_000,
+            gas_limit: 30_000_000,
+            gas_used: 0,
+            timestamp: 1_705_000_000,
+            extra_data: Default::default(),
+            mix_hash: B256::ZERO,
+            nonce: Default::default(),
+            base_fee_per_gas: U256::ZERO,
+            withdrawals_root: None,
+            blob_gas_used: None,
+            excess_blob_gas: None,
+            parent_beacon_block_root: None,
+        }
+    }
+
+    #[test]
+    fn snapshot_fork_is_its_block() {
+        let header = header();
+        let meta = BlockchainDbMeta::new(ChainSpec::new(1), header.clone());
+        let fork = ForkArgs::default().snapshot_fork(&meta).unwrap();
+        assert_eq!(fork.chain_id, 1);
+        assert_eq!(fork.header, header);
+        assert_eq!(fork.env_header, header);
+
+        let args = ForkArgs { block_number: Some(header.number), ..Default::default() };
+        assert!(args.snapshot_fork(&meta).is_ok());
+        let args = ForkArgs { env_block: Some(header.number + 1), ..Default::default() };
+        assert!(args.snapshot_fork(&meta).is_err());
+        let args = ForkArgs { timestamp: Some(1_700_000_000), ..Default::default() };
+        assert!(args.snapshot_fork(&meta).is_err());
+    }
+}
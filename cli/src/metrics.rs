@@ -0,0 +1,112 @@
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+use anyhow::Result;
+
+/// Name, help and value of a gauge
+type Sample = (&'static str, &'static str, f64);
+
+/// The metrics of a proving run in the Prometheus text format, served over http while the
+/// prover runs as a service, or written for the textfile collector of node_exporter.
+/// Clones share the same samples, so the endpoint sees the phases as they complete.
+#[derive(Debug, Default, Clone)]
+pub struct Metrics {
+    samples: Arc<Mutex<Vec<Sample>>>,
+}
+
+impl Metrics {
+    pub fn gauge(&self, name: &'static str, help: &'static str, value: f64) {
+        let mut samples = self.samples.lock().unwrap();
+        match samples.iter_mut().find(|(n, _, _)| *n == name) {
+            Some(sample) => sample.2 = value,
+            None => samples.push((name, help, value)),
+        }
+    }
+
+    pub fn duration(&self, name: &'static str, help: &'static str, duration: Duration) {
+        self.gauge(name, help, duration.as_secs_f64());
+    }
+
+    pub fn write<W: Write>(&self, mut output: W) -> Result<()> {
+        for (name, help, value) in self.samples.lock().unwrap().iter() {
+            writeln!(output, "# HELP zkprover_{} {}", name, help)?;
+            writeln!(output, "# TYPE zkprover_{} gauge", name)?;
+            writeln!(output, "zkprover_{} {}", name, value)?;
+        }
+        Ok(())
+    }
+
+    /// Serves the metrics on `addr` from a background thread for as long as the process
+    /// lives. Every path answers with the metrics, returns the address bound to.
+    pub fn serve(&self, addr: SocketAddr) -> Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let metrics = self.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if let Err(e) = metrics.respond(stream) {
+                    log::debug!("metrics request failed: {}", e);
+                }
+            }
+        });
+        Ok(local_addr)
+    }
+
+    fn respond(&self, mut stream: TcpStream) -> Result<()> {
+        let mut request = [0u8; 1024];
+        let _ = stream.read(&mut request)?;
+        let mut body = Vec::new();
+        self.write(&mut body)?;
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        )?;
+        stream.write_all(&body)?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(metrics: &Metrics) -> String {
+        let mut output = Vec::new();
+        metrics.write(&mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn records_the_durations_of_a_run() {
+        let metrics = Metrics::default();
+        metrics.duration("preflight_seconds", "Duration of the preflight", Duration::from_millis(1500));
+        metrics.duration("prove_seconds", "Duration of proving", Duration::from_secs(90));
+        metrics.duration("prove_seconds", "Duration of proving", Duration::from_secs(60));
+
+        let text = render(&metrics);
+        assert!(text.contains("# TYPE zkprover_preflight_seconds gauge\nzkprover_preflight_seconds 1.5\n"));
+        assert!(text.contains("zkprover_prove_seconds 60\n"));
+        assert_eq!(text.lines().filter(|line| line.starts_with("zkprover_prove_seconds ")).count(), 1);
+    }
+
+    #[test]
+    fn serves_the_samples_recorded_after_it_started() {
+        let metrics = Metrics::default();
+        let addr = metrics.serve("127.0.0.1:0".parse().unwrap()).unwrap();
+        metrics.gauge("user_cycles", "Cycles of the guest execution", 1024.0);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(&render(&metrics)));
+        assert!(response.contains("zkprover_user_cycles 1024\n"));
+    }
+}
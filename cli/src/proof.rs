@@ -1,33 +1,228 @@
 use std::io::{Read, Write};
-use alloy_primitives::B256;
-use anyhow::Result;
+use alloy_primitives::{B256, U256};
+use anyhow::{bail, Context, Result};
+use bridge::ExploitOutput;
 use serde::{Serialize, Deserialize};
 use chains_evm_core::deal::DealRecord;
+use guests::EXPLOIT_ID;
 use risc0_zkvm::Receipt;
 
 
+/// The id of a guest image. risc0 takes it as 8 words, a proof records the hex of their
+/// little endian bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImageId([u32; 8]);
+
+impl ImageId {
+    pub const fn new(words: [u32; 8]) -> Self {
+        Self(words)
+    }
+
+    pub fn from_hex(image_id: &str) -> Result<Self> {
+        let bytes = hex::decode(image_id).with_context(|| format!("Malformed image id {}", image_id))?;
+        if bytes.len() != 32 {
+            bail!("The image id {} is not 32 bytes", image_id)
+        }
+        let mut words = [0u32; 8];
+        for (word, chunk) in words.iter_mut().zip(bytes.chunks_exact(4)) {
+            *word = u32::from_le_bytes(chunk.try_into()?);
+        }
+        Ok(Self(words))
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.as_bytes())
+    }
+
+    pub fn as_words(&self) -> &[u32; 8] {
+        &self.0
+    }
+
+    /// The words as little endian bytes
+    pub fn as_bytes(&self) -> &[u8] {
+        bytemuck::cast_slice(&self.0)
+    }
+}
+
+/// The image id of the guest of this binary
+pub const EXPLOIT_IMAGE_ID: ImageId = ImageId::new(EXPLOIT_ID);
+
+/// Image ids of the guests whose proofs this binary can verify, the current guest first.
+/// When a release changes the guest without changing `ExploitOutput`, keep the id of the
+/// previous guest here so its proofs still verify.
+pub const COMPATIBLE_IMAGE_IDS: &[ImageId] = &[EXPLOIT_IMAGE_ID];
+
+
+/// A proof file starts with this magic and the format version, then the bincode of [Proof].
+/// The proofs of zkProver 0.3.0 and earlier have no header, they are format v0.
+const PROOF_MAGIC: [u8; 4] = *b"ZKPF";
+
+/// Bump whenever a field of [Proof] or of a type it holds changes, bincode can't tell.
+pub const PROOF_FORMAT_VERSION: u32 = 1;
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Proof {
     pub version: String,
     pub image_id: String,
     pub chain_id: u64,
     pub spec_id: String,
+    /// The block whose state the exploit reads
     pub block_number: u64,
+    /// The block whose env the exploit ran in, `block_number` unless `--env-block` was given
+    pub env_block_number: u64,
     pub poc_code_hash: B256,
     pub deals: Vec<DealRecord>,
+    /// The ether balance of the poc contract before the exploit
+    pub initial_balance: U256,
+    /// The solc version that compiled the poc
+    pub solc_version: String,
+    /// The evm version the poc was compiled for
+    pub evm_version: String,
     pub receipt: Option<Receipt>,
 }
 
 
 
 impl Proof {
-    pub fn load<R: Read>(input: R) -> Result<Self> {
-        let data = bincode::deserialize_from(input)?;
+    pub fn load<R: Read>(mut input: R) -> Result<Self> {
+        let mut header = [0u8; 8];
+        input.read_exact(&mut header).context("the proof file is truncated")?;
+        let format = match header[..4] == PROOF_MAGIC {
+            true => u32::from_le_bytes(header[4..].try_into()?),
+            false => 0,
+        };
+        if format != PROOF_FORMAT_VERSION {
+            bail!(
+                "proof format v{} not supported, this zkProver reads v{}, verify the proof with the zkProver that produced it",
+                format, PROOF_FORMAT_VERSION
+            )
+        }
+        let data = bincode::deserialize_from(input).context("malformed proof")?;
         Ok(data)
     }
 
-    pub fn save<W: Write>(&self, output: W) -> Result<()> {
+    pub fn save<W: Write>(&self, mut output: W) -> Result<()> {
+        output.write_all(&PROOF_MAGIC)?;
+        output.write_all(&PROOF_FORMAT_VERSION.to_le_bytes())?;
         bincode::serialize_into(output, self)?;
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// The size of the saved proof in bytes
+    pub fn size(&self) -> Result<u64> {
+        Ok(8 + bincode::serialized_size(self)?)
+    }
+
+    /// Fails when the saved proof would be larger than `max_size` bytes.
+    pub fn check_size(&self, max_size: u64) -> Result<()> {
+        let size = self.size()?;
+        if size > max_size {
+            bail!(
+                "The proof is {} bytes, more than the limit {}, compress the receipt into a succinct or groth16 receipt",
+                size, max_size
+            )
+        }
+        Ok(())
+    }
+
+    /// Whether the proof was produced by a guest this binary can verify
+    pub fn is_compatible(&self) -> bool {
+        COMPATIBLE_IMAGE_IDS.iter().any(|id| id.to_hex() == self.image_id)
+    }
+
+    /// Decodes the exploit output committed in the receipt journal, without verifying
+    /// the receipt.
+    pub fn output(&self) -> Result<ExploitOutput> {
+        let receipt = self.receipt.as_ref().context("the proof has no receipt")?;
+        Ok(receipt.journal.decode()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy_primitives::Address;
+
+    fn proof() -> Proof {
+        Proof {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            image_id: EXPLOIT_IMAGE_ID.to_hex(),
+            chain_id: 1,
+            spec_id: "SHANGHAI".to_string(),
+            block_number: 19_000_000,
+            env_block_number: 19_000_001,
+            poc_code_hash: B256::repeat_byte(1),
+            deals: vec!["1ether".parse().unwrap()],
+            initial_balance: U256::from(10).pow(U256::from(18)),
+            solc_version: "0.8.20".to_string(),
+            evm_version: "shanghai".to_string(),
+            receipt: None,
+        }
+    }
+
+    #[test]
+    fn image_id_hex_is_its_little_endian_words() {
+        let image_id = ImageId::new([1, 2, 3, 4, 5, 6, 7, 0xdeadbeef]);
+        let hex = image_id.to_hex();
+        assert!(hex.starts_with("0100000002000000"));
+        assert!(hex.ends_with("efbeadde"));
+        assert_eq!(ImageId::from_hex(&hex).unwrap(), image_id);
+
+        assert!(ImageId::from_hex("zz").is_err());
+        assert!(ImageId::from_hex(&hex[..62]).is_err());
+    }
+
+    #[test]
+    fn saved_proof_loads_back() {
+        let proof = proof();
+        let mut data = Vec::new();
+        proof.save(&mut data).unwrap();
+        assert_eq!(data.len() as u64, proof.size().unwrap());
+
+        let loaded = Proof::load(data.as_slice()).unwrap();
+        assert_eq!(loaded.env_block_number, proof.env_block_number);
+        assert_eq!(loaded.solc_version, proof.solc_version);
+        assert_eq!(loaded.evm_version, proof.evm_version);
+        assert_eq!(loaded.initial_balance, proof.initial_balance);
+        assert_eq!(loaded.deals, proof.deals);
+    }
+
+    /// The proof of zkProver 0.3.0 and earlier
+    #[derive(Serialize)]
+    struct ProofV0 {
+        version: String,
+        image_id: String,
+        chain_id: u64,
+        spec_id: String,
+        block_number: u64,
+        poc_code_hash: B256,
+        deals: Vec<(Address, U256)>,
+        receipt: Option<Receipt>,
+    }
+
+    #[test]
+    fn old_format_proof_is_rejected_with_its_version() {
+        let old = ProofV0 {
+            version: "0.3.0".to_string(),
+            image_id: EXPLOIT_IMAGE_ID.to_hex(),
+            chain_id: 1,
+            spec_id: "SHANGHAI".to_string(),
+            block_number: 19_000_000,
+            poc_code_hash: B256::repeat_byte(1),
+            deals: vec![(Address::ZERO, U256::from(1))],
+            receipt: None,
+        };
+        let data = bincode::serialize(&old).unwrap();
+        let err = Proof::load(data.as_slice()).unwrap_err();
+        assert!(err.to_string().starts_with("proof format v0 not supported"), "{}", err);
+    }
+
+    #[test]
+    fn newer_format_proof_is_rejected_with_its_version() {
+        let mut data = Vec::new();
+        proof().save(&mut data).unwrap();
+        data[4..8].copy_from_slice(&(PROOF_FORMAT_VERSION + 1).to_le_bytes());
+        let err = Proof::load(data.as_slice()).unwrap_err();
+        assert!(err.to_string().starts_with(&format!("proof format v{} not supported", PROOF_FORMAT_VERSION + 1)), "{}", err);
+    }
+}
@@ -1,35 +1,164 @@
 use clap::Parser;
-use clio::{Input, OutputPath};
-use anyhow::Result;
-use std::io::Write;
-use alloy_provider::{Provider, ProviderBuilder};
-use alloy_rpc_types::BlockId;
-use alloy_primitives::U256;
+use clio::{Input, Output, OutputPath};
+use anyhow::{bail, Context, Result};
+use std::{collections::BTreeMap, io::{Read, Write}, path::PathBuf};
+use alloy_provider::ProviderBuilder;
+use alloy_primitives::{Address, B256, U256};
 use chains_evm_core::{
-    block::BlockHeader, db::{BlockchainDbMeta, ChainSpec, JsonBlockCacheDB}, deal::DealRecord, poc_compiler::compile_poc, preflight::build_input
+    inspector::{format_call_trace, format_call_tree}, db::{ChainSpec, JsonBlockCacheDB}, deal::token_deal_storage, poc_compiler::{compile_poc, compile_poc_with, EVM_VERSION, SOLC_VERSION}, preflight::{build_input, check_profit, check_reproducible, check_state_entries, resim_exploit, PreflightConfig},
+    summary::summarize_exploit,
 };
-use risc0_zkvm::{serde::to_vec, Receipt};
-use crate::proof::Proof;
-use guests::EXPLOIT_ID;
+use risc0_zkvm::{serde::{from_slice, to_vec}, Receipt};
+use bridge::{ExploitInput, ExploitOutput, MemDB, DEFAULT_CONTRACT_ADDRESS};
+use serde::Serialize;
+use crate::proof::{Proof, EXPLOIT_IMAGE_ID};
+use crate::exploit::ExploitArgs;
+use crate::fork::ForkArgs;
+
+
+/// Writes the input of the guest in the format `Pack` expects
+pub fn save_input<W: Write>(input: &ExploitInput, mut output: W) -> Result<()> {
+    let mut v8bytes: Vec<u8> = Vec::new();
+    v8bytes.extend_from_slice(bytemuck::cast_slice(&to_vec(input)?));
+    output.write_all(&v8bytes)?;
+    Ok(())
+}
+
+/// An account of the committed state, without its code
+#[derive(Debug, Serialize)]
+struct AccountDump {
+    balance: U256,
+    nonce: u64,
+    code_hash: B256,
+    storage: BTreeMap<U256, U256>,
+}
+
+/// The committed state of an input, readable json
+#[derive(Debug, Serialize)]
+struct MemDbDump {
+    accounts: BTreeMap<Address, AccountDump>,
+    block_hashes: BTreeMap<u64, B256>,
+}
+
+/// Writes the accounts, slots and block hashes committed in the input as pretty json,
+/// to check what the preflight traced.
+pub fn dump_memdb<W: Write>(db: &MemDB, output: W) -> Result<()> {
+    let dump = MemDbDump {
+        accounts: db.accounts.iter().map(|(address, account)| {
+            (*address, AccountDump {
+                balance: account.info.balance,
+                nonce: account.info.nonce,
+                code_hash: account.info.code_hash,
+                storage: account.storage.clone(),
+            })
+        }).collect(),
+        block_hashes: db.block_hashes.iter().cloned().collect(),
+    };
+    serde_json::to_writer_pretty(output, &dump)?;
+    Ok(())
+}
+
+/// Reads an input written by [save_input]
+pub fn load_input<R: Read>(mut input: R) -> Result<ExploitInput> {
+    let mut v8bytes: Vec<u8> = Vec::new();
+    input.read_to_end(&mut v8bytes)?;
+    if v8bytes.len() % 4 != 0 {
+        bail!("Malformed guest input, its length is not a multiple of 4")
+    }
+    let words: Vec<u32> = bytemuck::pod_collect_to_vec(&v8bytes);
+    Ok(from_slice(&words)?)
+}
+
+/// Magic of the file bundling the guest input with its sketch proof
+const BUNDLE_MAGIC: &[u8; 4] = b"ZKPB";
+
+/// Writes the guest input and its sketch proof as length prefixed sections of one file, so
+/// the pair can't get mixed up.
+pub fn save_bundle<W: Write>(input: &ExploitInput, proof: &Proof, mut output: W) -> Result<()> {
+    let mut input_bytes = Vec::new();
+    save_input(input, &mut input_bytes)?;
+    let mut proof_bytes = Vec::new();
+    proof.save(&mut proof_bytes)?;
+    output.write_all(BUNDLE_MAGIC)?;
+    for section in [&input_bytes, &proof_bytes] {
+        output.write_all(&(section.len() as u64).to_le_bytes())?;
+        output.write_all(section)?;
+    }
+    Ok(())
+}
+
+/// Reads a bundle written by [save_bundle], returns the guest input and the sketch proof.
+/// Fails when the sketch proof was not written for the input.
+pub fn load_bundle<R: Read>(mut input: R) -> Result<(ExploitInput, Proof)> {
+    let mut magic = [0u8; 4];
+    input.read_exact(&mut magic)?;
+    if &magic != BUNDLE_MAGIC {
+        bail!("Not an input bundle written by `pre --bundle`")
+    }
+    let exploit_input = load_input(read_section(&mut input)?.as_slice())?;
+    let proof = Proof::load(read_section(&mut input)?.as_slice())?;
+    check_bundle(&exploit_input, &proof)?;
+    Ok((exploit_input, proof))
+}
+
+/// Reads a length prefixed section. The length isn't trusted, a corrupt one only reads up
+/// to the end of the file.
+fn read_section<R: Read>(input: &mut R) -> Result<Vec<u8>> {
+    let mut len = [0u8; 8];
+    input.read_exact(&mut len).context("The bundle is truncated")?;
+    let len = u64::from_le_bytes(len);
+    let mut section = Vec::new();
+    input.take(len).read_to_end(&mut section)?;
+    if section.len() as u64 != len {
+        bail!("The bundle is truncated, a section of {} bytes has only {}", len, section.len())
+    }
+    Ok(section)
+}
+
+/// Fails when the sketch proof doesn't describe the input: another chain, hardfork, env
+/// block or poc.
+fn check_bundle(input: &ExploitInput, proof: &Proof) -> Result<()> {
+    let spec_name: &'static str = input.spec_id.into();
+    let poc_code_hash = input.db.accounts.get(&DEFAULT_CONTRACT_ADDRESS).map(|poc| poc.info.code_hash);
+    if proof.chain_id != input.chain_id
+        || proof.spec_id != spec_name
+        || U256::from(proof.env_block_number) != input.block_env.number
+        || Some(proof.poc_code_hash) != poc_code_hash
+    {
+        bail!("The sketch proof of the bundle was not written for its input")
+    }
+    Ok(())
+}
 
 
 #[derive(Parser, Debug)]
 pub struct PreArgs {
     poc: String,
 
-    #[clap(short, long)]
-    rpc_url: String,
+    #[clap(flatten)]
+    fork: ForkArgs,
 
-    #[clap(short, long)]
-    block_number: Option<u64>,
-    /// Set the balances of the exploit contract.
-    /// Examples: 1ether, 0xdac17f958d2ee523a2206206994597c13d831ec7:10gwei
-    #[clap(short, long)]
-    deal: Option<Vec<DealRecord>>,
+    #[clap(flatten)]
+    exploit: ExploitArgs,
 
-    /// limit the max gas used
-    #[clap(short, long)]
-    gas: Option<u64>,
+    /// Fail if the committed state has more entries than this
+    #[clap(long)]
+    max_state_entries: Option<usize>,
+    /// Fail before proving if the poc contract gains nothing in any token
+    #[clap(long)]
+    fail_on_no_profit: bool,
+    /// Print the gas the exploit spent on storage, calls and computation
+    #[clap(long)]
+    gas_breakdown: bool,
+    /// When the exploit reverts, print its calls up to the revert along with the reason
+    #[clap(long)]
+    continue_on_revert: bool,
+    /// Print the calls of the exploit as a tree
+    #[clap(long)]
+    trace: bool,
+    /// Build the input twice and fail unless both are the same bytes
+    #[clap(long)]
+    check_reproducible: bool,
 
     /// Output file
     #[clap(long, short, value_parser, default_value = "input.hex")]
@@ -37,6 +166,54 @@ pub struct PreArgs {
 
     #[clap(long, short, value_parser, default_value = "sketch_proof.bin")]
     proof: OutputPath,
+
+    /// Also write the input and the sketch proof together in one file, `pack --bundle` reads it
+    #[clap(long, value_parser)]
+    bundle: Option<OutputPath>,
+
+    /// Write the accounts and slots the exploit touches as an EIP-2930 access list json
+    #[clap(long, value_parser)]
+    access_list: Option<OutputPath>,
+
+    /// Write the committed accounts, slots and block hashes as json
+    #[clap(long, value_parser)]
+    dump_memdb: Option<OutputPath>,
+}
+
+#[derive(Parser, Debug)]
+pub struct SnapshotArgs {
+    poc: String,
+
+    #[clap(flatten)]
+    fork: ForkArgs,
+
+    /// The snapshot holds the state the exploit reads with these flags, prove it with the same
+    #[clap(flatten)]
+    exploit: ExploitArgs,
+
+    /// Output file
+    #[clap(long, short, value_parser, default_value = "snapshot.json")]
+    output: OutputPath,
+}
+
+#[derive(Parser, Debug)]
+pub struct ResimArgs {
+    /// proof file
+    path: Input,
+
+    /// The source of the poc, the proof doesn't carry its code
+    #[clap(long)]
+    poc: PathBuf,
+}
+
+#[derive(Parser, Debug)]
+pub struct DecodeJournalArgs {
+    /// A bare receipt, as `pack` takes it
+    receipt: Input,
+
+    /// Output file
+    #[clap(long, short, value_parser, default_value = "-")]
+    output: Output,
 }
 
 #[derive(Parser, Debug)]
@@ -47,63 +224,100 @@ pub struct PackArgs {
     #[clap(long, value_parser, default_value = "sketch_proof.bin")]
     proof: Input,
 
+    /// Take the sketch proof from a bundle written by `pre --bundle` instead of `--proof`
+    #[clap(long, value_parser, conflicts_with = "proof")]
+    bundle: Option<Input>,
+
     #[clap(long, value_parser, default_value = "proof.bin")]
     output: OutputPath,
+
+    /// Fail instead of writing a proof larger than this, in bytes
+    #[clap(long)]
+    max_proof_size: Option<u64>,
 }
 
 impl PreArgs {
     pub async fn run(self) -> Result<()> {
-        let contract = compile_poc(self.poc)?;
-        let poc_code_hash = contract.hash_slow();
-
-        let provider = ProviderBuilder::new()
-            .on_http(self.rpc_url.as_str().try_into()?)?;
+        let poc = compile_poc(self.poc)?;
+        let poc_code_hash = poc.bytecode.hash_slow();
 
-        let block_id = match self.block_number {
-            Some(n) => BlockId::number(n),
-            None => BlockId::safe()
+        let rpc_url = self.fork.rpc_url()?;
+        let provider;
+        let (fork, db) = match rpc_url.strip_prefix("file://") {
+            // a snapshot exported by `snapshot` serves every read, no node is needed
+            Some(path) => {
+                let db = JsonBlockCacheDB::from_snapshot(path)?;
+                (self.fork.snapshot_fork(&db.data().meta)?, db)
+            }
+            None => {
+                provider = ProviderBuilder::new()
+                    .on_http(rpc_url.as_str().try_into()?)?;
+                let fork = self.fork.resolve(&provider).await?;
+                let db = fork.open_db(&provider)?;
+                (fork, db)
+            }
         };
-        let chain_id = provider.get_chain_id().await?;
-        let block = provider.get_block(block_id, false).await?.expect("could not found block");
-        let block_number = block.header.number.unwrap();
+        let (chain_id, block_number, env_header) = (fork.chain_id, fork.header.number, fork.env_header.clone());
 
-        let rpc_cache_dir = dirs_next::home_dir().expect("home dir not found").join(".securfi").join("cache").join("rpc");
-        let cache_path =  rpc_cache_dir.join(format!("{}", chain_id)).join(format!("{}.json", block.header.number.unwrap()));
-
-        let header: BlockHeader = block.header.try_into()?;
-
-        let chain_spec = ChainSpec::mainnet();
-        let meta = BlockchainDbMeta {
-            chain_spec: chain_spec.clone(), // currently only supports mainnet and shanghai
-            header: header.clone(),
+        let config = PreflightConfig {
+            gas_breakdown: self.gas_breakdown,
+            trace_revert: self.continue_on_revert,
+            trace: self.trace,
+            ..self.exploit.config(chain_id, &poc)?
         };
-        let db = JsonBlockCacheDB::new(&provider, meta, Some(cache_path));
-
-        // todo: add deal
-        let initial_balance = U256::ZERO;
-        let exploit_input = build_input(contract, header, &db, initial_balance)?;
+        let (exploit_input, report) = build_input(poc.bytecode.clone(), env_header.clone(), &db, &config)?;
+        if self.check_reproducible {
+            check_reproducible(&exploit_input, poc.bytecode, env_header.clone(), &db, &config)?;
+        }
+        for warning in report.warnings() {
+            println!("Warning: {}", warning);
+        }
+        if self.trace {
+            println!("{}", format_call_tree(&report.calls, config.poc_abi.as_ref()));
+        }
+        if let Some(breakdown) = report.gas_breakdown {
+            println!(
+                "Gas breakdown: storage {}, calls {}, computation {}",
+                breakdown.storage, breakdown.calls, breakdown.computation
+            );
+        }
+        if let Some(max_state_entries) = self.max_state_entries {
+            check_state_entries(&exploit_input, max_state_entries)?;
+        }
+        if self.fail_on_no_profit {
+            check_profit(&exploit_input, &ChainSpec::new(chain_id))?;
+        }
+        if let Some(path) = self.access_list {
+            serde_json::to_writer(path.create()?, &report.access_list)?;
+        }
+        if let Some(path) = self.dump_memdb {
+            dump_memdb(&exploit_input.db, path.create()?)?;
+        }
 
 
-        let mut v8bytes: Vec<u8> = Vec::new();
-        v8bytes.extend_from_slice(bytemuck::cast_slice(&to_vec(&exploit_input).unwrap()));
-        let mut output = self.output.create()?;
-        output.write_all(&v8bytes)?;
+        save_input(&exploit_input, self.output.create()?)?;
 
-        let spec_name: &'static str = chain_spec.spec_id.into();
-        let image_id = hex::encode(EXPLOIT_ID.iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<u8>>());
+        let spec_name: &'static str = exploit_input.spec_id.into();
 
         let proof = Proof {
             version: env!("CARGO_PKG_VERSION").to_string(),
-            image_id: image_id,
+            image_id: EXPLOIT_IMAGE_ID.to_hex(),
             chain_id: chain_id,
             spec_id: spec_name.to_string(),
             block_number: block_number,
+            env_block_number: env_header.number,
             poc_code_hash: poc_code_hash,
-            deals: self.deal.unwrap_or_default(),
+            deals: config.deals.clone(),
+            initial_balance: config.initial_balance,
+            solc_version: SOLC_VERSION.to_string(),
+            evm_version: EVM_VERSION.to_string(),
             receipt: None,
         };
         let output = self.proof.create()?;
         proof.save(output)?;
+        if let Some(bundle) = self.bundle {
+            save_bundle(&exploit_input, &proof, bundle.create()?)?;
+        }
         
         return Ok(());
     }
@@ -111,11 +325,161 @@ impl PreArgs {
 
 impl PackArgs {
     pub fn run(self) -> Result<()> {
-        let mut proof = Proof::load(self.proof)?;
+        let mut proof = match self.bundle {
+            Some(bundle) => load_bundle(bundle)?.1,
+            None => Proof::load(self.proof)?,
+        };
         let receipt: Receipt = bincode::deserialize_from(self.receipt)?;
         proof.receipt = Some(receipt);
+        if let Some(max_proof_size) = self.max_proof_size {
+            proof.check_size(max_proof_size)?;
+        }
         let output = self.output.create()?;
         proof.save(output)?;
+        println!("Proof size: {} bytes", proof.size()?);
+        return Ok(());
+    }
+}
+
+impl SnapshotArgs {
+    pub async fn run(self) -> Result<()> {
+        let poc = compile_poc(self.poc)?;
+
+        let provider = ProviderBuilder::new()
+            .on_http(self.fork.rpc_url()?.as_str().try_into()?)?;
+
+        let fork = self.fork.resolve(&provider).await?;
+        // the snapshot only holds the state block, the proof from it runs in that env
+        if fork.env_header.number != fork.header.number {
+            bail!("A snapshot only holds block {}, --env-block can't be proven from it", fork.header.number)
+        }
+        let db = fork.open_db(&provider)?;
+
+        let config = self.exploit.config(fork.chain_id, &poc)?;
+        let (exploit_input, _) = build_input(poc.bytecode, fork.env_header.clone(), &db, &config)?;
+
+        let mut snapshot = db.data().snapshot(&exploit_input.db);
+        // the token deals find their balance slots with reads of their own
+        let deal_db = db.fresh();
+        token_deal_storage(&deal_db, &config.deals)?;
+        let reads = deal_db.data();
+        snapshot.accounts.extend(reads.accounts);
+        for (address, storage) in reads.storage {
+            snapshot.storage.entry(address).or_default().extend(storage);
+        }
+        serde_json::to_writer(self.output.create()?, &snapshot)?;
+        return Ok(());
+    }
+}
+
+impl ResimArgs {
+    pub fn run(mut self) -> Result<()> {
+        let proof = Proof::load(&mut self.path)?;
+        let contract = compile_poc_with(&self.poc, &proof.solc_version, &proof.evm_version)?.bytecode;
+        if contract.hash_slow() != proof.poc_code_hash {
+            bail!("{} doesn't compile to the poc of the proof", self.poc.display())
+        }
+        let mut input = proof.output()?.input;
+        let poc = input.db.accounts.get_mut(&DEFAULT_CONTRACT_ADDRESS).context("the proof has no poc contract")?;
+        poc.info.code = Some(contract);
+
+        let (result_and_state, tracer) = resim_exploit(&input)?;
+        println!("{}", format_call_trace(&tracer.calls));
+        println!("Gas used: {}", result_and_state.result.gas_used());
         return Ok(());
     }
 }
+
+impl DecodeJournalArgs {
+    pub fn run(self) -> Result<()> {
+        let receipt: Receipt = bincode::deserialize_from(self.receipt)?;
+        let output: ExploitOutput = receipt.journal.decode()?;
+        let summary = summarize_exploit(&output, None)?;
+        serde_json::to_writer_pretty(self.output, &summary)?;
+        return Ok(());
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bridge::{AccountStorage, CALL_EXPLOIT_DATA};
+    use revm_primitives::{AccountInfo, BlockEnv, Bytecode, SpecId};
+
+    fn input() -> ExploitInput {
+        let code = Bytecode::new_raw(alloy_primitives::bytes!("600160005500"));
+        let mut input = ExploitInput {
+            db: MemDB::default(),
+            block_env: BlockEnv { number: U256::from(19_000_001), ..Default::default() },
+            spec_id: SpecId::SHANGHAI,
+            chain_id: 1,
+            gas_price: U256::from(1),
+            gas_limit: 100_000,
+            blob_hashes: Vec::new(),
+            max_fee_per_blob_gas: None,
+            setup_txs: Vec::new(),
+            expect_revert: false,
+            calldata: CALL_EXPLOIT_DATA,
+        };
+        let poc = AccountInfo::new(U256::ZERO, 1, code.hash_slow(), code);
+        input.db.accounts.insert(DEFAULT_CONTRACT_ADDRESS, AccountStorage { info: poc, ..Default::default() });
+        input
+    }
+
+    /// The sketch proof `pre` writes for the input
+    fn sketch_proof(input: &ExploitInput) -> Proof {
+        let spec_name: &'static str = input.spec_id.into();
+        Proof {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            image_id: EXPLOIT_IMAGE_ID.to_hex(),
+            chain_id: input.chain_id,
+            spec_id: spec_name.to_string(),
+            block_number: 19_000_000,
+            env_block_number: input.block_env.number.to::<u64>(),
+            poc_code_hash: input.db.accounts[&DEFAULT_CONTRACT_ADDRESS].info.code_hash,
+            deals: Vec::new(),
+            initial_balance: U256::ZERO,
+            solc_version: SOLC_VERSION.to_string(),
+            evm_version: EVM_VERSION.to_string(),
+            receipt: None,
+        }
+    }
+
+    #[test]
+    fn bundle_loads_back() {
+        let input = input();
+        let mut bundle = Vec::new();
+        save_bundle(&input, &sketch_proof(&input), &mut bundle).unwrap();
+
+        let (loaded, proof) = load_bundle(bundle.as_slice()).unwrap();
+        assert_eq!(loaded, input);
+        assert_eq!(proof.block_number, 19_000_000);
+        assert_eq!(proof.solc_version, SOLC_VERSION);
+        assert!(proof.receipt.is_none());
+    }
+
+    #[test]
+    fn truncated_or_corrupt_bundle_fails() {
+        let input = input();
+        let mut bundle = Vec::new();
+        save_bundle(&input, &sketch_proof(&input), &mut bundle).unwrap();
+
+        assert!(load_bundle(&bundle[..bundle.len() - 1]).is_err());
+        let mut corrupt = bundle.clone();
+        corrupt[4..12].copy_from_slice(&u64::MAX.to_le_bytes());
+        let err = load_bundle(corrupt.as_slice()).unwrap_err();
+        assert!(err.to_string().starts_with("The bundle is truncated"), "{}", err);
+    }
+
+    #[test]
+    fn bundle_of_a_mismatched_pair_fails() {
+        let input = input();
+        let mut proof = sketch_proof(&input);
+        proof.chain_id = 10;
+        let mut bundle = Vec::new();
+        save_bundle(&input, &proof, &mut bundle).unwrap();
+        let err = load_bundle(bundle.as_slice()).unwrap_err();
+        assert_eq!(err.to_string(), "The sketch proof of the bundle was not written for its input");
+    }
+}
@@ -1,23 +1,31 @@
+use std::{collections::BTreeMap, fs::File, io::{BufWriter, Write}, path::PathBuf};
 use clap::Parser;
 use clio::{Input, Output};
-use anyhow::{Result, bail};
-use hex::FromHex;
+use anyhow::{Context, Result, bail};
 use revm_primitives::db::DatabaseRef;
 use serde::{Deserialize, Serialize};
 use alloy_rpc_types::BlockId;
 use alloy_provider::{Provider, ProviderBuilder};
-use alloy_primitives::{B256, U256, Address};
-use bridge::{DEFAULT_CONTRACT_ADDRESS, DEFAULT_CALLER};
+use alloy_primitives::{Address, Bytes, B256, I256, U256};
+use bridge::{exploit_outcome, CALL_EXPLOIT_DATA, gas_refunded, try_sim_exploit, ExploitInput, ExploitOutput, SetupTx, DEFAULT_CONTRACT_ADDRESS, DEFAULT_CALLER};
 use chains_evm_core::{
-    balance_change::{compute_asset_change, AssetChange},
+    balance_change::{
+        reconcile_transfers, token_metadata, AssetChange, GainExpectation, TokenGain
+    },
     block::BlockHeader,
     db::{BlockchainDbMeta, ChainSpec, JsonBlockCacheDB},
-    deal::DealRecord,
-    state_diff::{compute_state_diff, StateDiff}
+    deal::{ether_balance, token_deal_storage, DealRecord},
+    poc_compiler::compile_poc_with,
+    preflight::resim_exploit,
+    slot_label::label_slots,
+    state_diff::{compute_state_diff, StateDiff},
+    summary::{compute_exploit_stats, summarize_exploit, ExploitStats, ExploitSummary},
+    utils::{decode_revert, explain_evm_error, format_token_amount},
 };
-use risc0_zkvm::sha::Digest;
-use bridge::ExploitOutput;
-use crate::proof::Proof;
+use crate::fork::rpc_cache_dir;
+use crate::proof::{ImageId, Proof};
+use crate::rpc_url;
+use crate::tools::load_input;
 
 
 #[derive(Parser, Debug)]
@@ -29,115 +37,557 @@ pub struct VerifyArgs {
     #[clap(long, short, value_parser, default_value = "-")]
     output: Output,
 
+    /// Pretty print the result instead of writing compact json
+    #[clap(long)]
+    pretty: bool,
+
+    /// The rpc url, or `file://<path>` of a snapshot exported by `snapshot` to verify
+    /// without a node. Defaults to the `SECURFI_RPC_URL` or `ETH_RPC_URL` environment variable
     #[clap(short, long)]
-    rpc_url: String,
+    rpc_url: Option<String>,
+
+    /// Count the chain's wrapped native token (e.g. WETH) as ether in the net gain.
+    #[clap(long)]
+    combine_wrapped_native: bool,
+
+    /// Override the wrapped native token of the chain.
+    #[clap(long, requires = "combine_wrapped_native")]
+    wrapped_native: Option<Address>,
+
+    /// Label the changed storage slots whose layout is known, like proxy slots and
+    /// mapping entries keyed by the touched accounts.
+    #[clap(long)]
+    label_slots: bool,
+
+    /// Fail unless the poc contract gains this much, e.g. `>=1ether` or `<token>:>1000000`
+    #[clap(long)]
+    expect_gain: Vec<GainExpectation>,
+
+    /// The source of the poc, checks that it compiles to the proven poc code with the
+    /// recorded solc and evm version, and that the committed state is enough to run it again
+    #[clap(long)]
+    poc: Option<PathBuf>,
+
+    /// Print every check as it runs and report all failed checks instead of stopping at
+    /// the first one.
+    #[clap(long)]
+    explain: bool,
+
+    /// The guest input written by `pre`, to verify a sketch proof without a receipt by
+    /// running the exploit on the host. The result is marked as unproven.
+    #[clap(long)]
+    input: Option<PathBuf>,
+
+    /// Only compute the state diff, skipping the token balance queries of the asset change
+    #[clap(long, conflicts_with_all = ["only_asset_change", "expect_gain", "combine_wrapped_native"])]
+    only_state_diff: bool,
+
+    /// Only compute the asset change, not the state diff
+    #[clap(long, conflicts_with = "label_slots")]
+    only_asset_change: bool,
+
+    /// Fail unless the proof proves the exploit reverts, optionally with this reason
+    #[clap(long)]
+    expect_revert: Option<Option<String>>,
 }
 
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct VerifyResult {
+    /// False for a sketch proof verified with `--input`, the exploit only ran on the host
+    pub proven: bool,
+    /// The decoded revert reason, for a proof that the exploit reverts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revert: Option<String>,
+    /// The calldata of the exploit tx, when it doesn't call `exploit()`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub calldata: Option<Bytes>,
     pub version: String,
     pub image_id: String,
     pub chain_id: u64,
     pub spec_id: String,
     pub block_number: u64,
+    /// The block whose env the exploit ran in, when it isn't `block_number`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env_block_number: Option<u64>,
     pub poc_code_hash: B256,
     pub deals: Vec<DealRecord>,
-    pub state_diff: StateDiff,
-    pub asset_change: Vec<AssetChange>,
+    pub initial_balance: U256,
+    /// The state diff and asset change include the effects of these txs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub setup_txs: Vec<SetupTx>,
+    /// [None] with `--only-asset-change`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state_diff: Option<StateDiff>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub slot_labels: BTreeMap<Address, BTreeMap<U256, String>>,
+    /// [None] with `--only-state-diff`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub asset_change: Option<Vec<AssetChange>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub net_gain: Option<Vec<TokenGain>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+    /// The checks that didn't run, e.g. the re-simulation without `--poc`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skipped_checks: Vec<String>,
     pub gas_used: u64,
+    pub gas_refunded: u64,
+    pub gas_price: U256,
+    /// The coinbase the exploit ran with, when it isn't the block's miner
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coinbase: Option<Address>,
+    /// The ether the exploit tx paid for gas, `gas_used * gas_price`
+    pub gas_fee: U256,
+    pub stats: ExploitStats,
+}
+
+
+/// Runs the exploit of a sketch proof on the host and commits what the guest would.
+fn sketch_output(input: ExploitInput) -> Result<ExploitOutput> {
+    let result_and_state = match try_sim_exploit(&input) {
+        Ok(result_and_state) => result_and_state,
+        Err(err) => bail!("Failed to run the exploit of the input: {}", explain_evm_error(&err)),
+    };
+    let Ok(revert) = exploit_outcome(&input, &result_and_state.result) else {
+        bail!("the exploit of the input doesn't end as expected")
+    };
+    let mut output = ExploitOutput {
+        gas_used: result_and_state.result.gas_used(),
+        gas_refunded: gas_refunded(&result_and_state.result),
+        logs: result_and_state.result.logs().to_vec(),
+        state: result_and_state.state,
+        revert: revert,
+        input: input,
+    };
+    if let Some(poc) = output.input.db.accounts.get_mut(&DEFAULT_CONTRACT_ADDRESS) {
+        poc.info.code = None;
+    }
+    Ok(output)
+}
+
+/// Whether the journal proves the revert: the input expects one, the exploit reverted, and
+/// with the reason of `--expect-revert` when it gives one.
+fn revert_as_expected(expect_revert: bool, revert: Option<&str>, expected: Option<&Option<String>>) -> bool {
+    match (revert, expected) {
+        (Some(reason), Some(Some(expected))) => expect_revert && reason == expected,
+        (Some(_), _) => expect_revert,
+        (None, _) => false,
+    }
+}
+
+/// The exploit may run in the env of a later block than its state, but no further than the
+/// 256 blocks `BLOCKHASH` can reach.
+pub fn env_block_in_window(block_number: u64, env_block_number: u64) -> bool {
+    env_block_number >= block_number && env_block_number - block_number <= 256
+}
+
+/// Runs the verification checks. It stops at the first failed check, unless in explain
+/// mode where every check is printed and all the failures are reported at the end.
+struct Checker {
+    explain: bool,
+    failures: Vec<String>,
+    skipped: Vec<String>,
+}
+
+impl Checker {
+    fn new(explain: bool) -> Self {
+        Self { explain, failures: Vec::new(), skipped: Vec::new() }
+    }
+
+    /// Records a check that can't run with the given args, it is reported in the result
+    /// so a pass without it isn't mistaken for a full one.
+    fn skip(&mut self, name: impl Into<String>, reason: &str) {
+        let name = name.into();
+        if self.explain {
+            eprintln!("- {} skipped, {}", name, reason);
+        } else {
+            eprintln!("Warning: {} skipped, {}", name, reason);
+        }
+        self.skipped.push(name);
+    }
+
+    fn check(&mut self, passed: bool, name: impl Into<String>) -> Result<()> {
+        let name = name.into();
+        if self.explain {
+            eprintln!("{} {}", if passed { "✓" } else { "✗" }, name);
+        }
+        if !passed {
+            if !self.explain {
+                bail!("{} is not correct", name)
+            }
+            self.failures.push(name);
+        }
+        Ok(())
+    }
+
+    /// Fails with every failed check, otherwise returns the skipped checks
+    fn finish(self) -> Result<Vec<String>> {
+        if !self.failures.is_empty() {
+            bail!("{} checks failed: {}", self.failures.len(), self.failures.join(", "))
+        }
+        Ok(self.skipped)
+    }
 }
 
 
-async fn verify(proof: Proof, rpc_url: String) -> Result<VerifyResult> {
-    let image_id = Digest::from_hex(proof.image_id.clone())?;
-    proof.receipt.clone().unwrap().verify(image_id)?;
+async fn verify(proof: Proof, args: &VerifyArgs) -> Result<VerifyResult> {
+    if !proof.is_compatible() {
+        bail!(
+            "the proof was produced by zkProver {} with an unknown guest {}, verify it with that version",
+            proof.version, proof.image_id
+        )
+    }
+    if proof.version != env!("CARGO_PKG_VERSION") {
+        eprintln!(
+            "Warning: the proof was produced by zkProver {}, this is {}",
+            proof.version, env!("CARGO_PKG_VERSION")
+        );
+    }
+    let mut checker = Checker::new(args.explain);
+    let output = match &proof.receipt {
+        Some(receipt) => {
+            let image_id = ImageId::from_hex(&proof.image_id)?;
+            checker.check(receipt.verify(*image_id.as_words()).is_ok(), "receipt")?;
+            proof.output()?
+        }
+        None => {
+            let path = args.input.as_ref().context("the proof has no receipt, pass the input of `pre` with --input")?;
+            eprintln!("Warning: the proof has no receipt, the result is unproven");
+            let input = load_input(File::open(path)?)?;
+            let code_hash = input.db.accounts.get(&DEFAULT_CONTRACT_ADDRESS)
+                .and_then(|poc| poc.info.code.as_ref())
+                .map(|code| code.hash_slow());
+            checker.check(code_hash == Some(proof.poc_code_hash), "poc code of the input")?;
+            sketch_output(input)?
+        }
+    };
+    let poc_code = match &args.poc {
+        Some(poc) => {
+            let contract = compile_poc_with(poc, &proof.solc_version, &proof.evm_version)?.bytecode;
+            checker.check(contract.hash_slow() == proof.poc_code_hash, "poc source")?;
+            Some(contract)
+        }
+        None => {
+            checker.skip("poc source", "pass the source of the poc with --poc");
+            None
+        }
+    };
+
+    let chain_spec = ChainSpec::new(proof.chain_id);
+    let rpc_url = rpc_url(args.rpc_url.clone())?;
+    // the exploit may run in the env of a later block than the state it reads
+    let env_block_number = proof.env_block_number;
+    checker.check(
+        env_block_in_window(proof.block_number, env_block_number),
+        format!("env block {} within 256 blocks after the state block", env_block_number),
+    )?;
+    checker.check(output.input.block_env.number == U256::from(env_block_number), "env block number")?;
+    let (rpc_db, header) = match rpc_url.strip_prefix("file://") {
+        Some(path) => {
+            let rpc_db = JsonBlockCacheDB::from_snapshot(path)?;
+            let meta = rpc_db.data().meta;
+            if meta.header.number != proof.block_number || meta.chain_spec.chain_id != proof.chain_id {
+                bail!(
+                    "the snapshot is of block {} on chain {}, the proof is of block {} on chain {}",
+                    meta.header.number, meta.chain_spec.chain_id, proof.block_number, proof.chain_id
+                )
+            }
+            if env_block_number != proof.block_number {
+                bail!("the exploit ran in the env of block {}, the snapshot only has block {}", env_block_number, proof.block_number)
+            }
+            (rpc_db, meta.header)
+        }
+        None => {
+            let block_id = BlockId::number(proof.block_number);
+            let provider = ProviderBuilder::new()
+                    .on_http(rpc_url.as_str().try_into()?)?;
 
-    let output: ExploitOutput = proof.receipt.unwrap().journal.decode()?;
-    let block_id = BlockId::number(proof.block_number);
-    let provider = ProviderBuilder::new()
-            .on_http(rpc_url.as_str().try_into()?)?;
+            let block = provider.get_block(block_id, false).await?.expect("could not found block");
+            let header: BlockHeader = block.header.try_into()?;
+            let env_header: BlockHeader = if env_block_number == proof.block_number {
+                header.clone()
+            } else {
+                provider.get_block(BlockId::number(env_block_number), false).await?
+                    .context("could not find the env block")?
+                    .header
+                    .try_into()?
+            };
 
-    let block = provider.get_block(block_id, false).await?.expect("could not found block");
-    let header: BlockHeader = block.header.try_into()?;
+            let cache_path = rpc_cache_dir(proof.chain_id).join(format!("{}.json", proof.block_number));
+            let meta = BlockchainDbMeta::new(chain_spec.clone(), header);
+            (JsonBlockCacheDB::new(provider, meta, Some(cache_path)), env_header)
+        }
+    };
 
-    if output.input.block_env != header.into_block_env() {
-        bail!("block env mismatch")
+    // the coinbase may be overridden by the prover, it is reported in the result
+    let mut block_env = header.into_block_env();
+    let coinbase = output.input.block_env.coinbase;
+    if coinbase != block_env.coinbase {
+        eprintln!("Warning: the exploit ran with the coinbase {} instead of {}", coinbase, block_env.coinbase);
+        block_env.coinbase = coinbase;
     }
+    checker.check(output.input.block_env == block_env, "block env")?;
+    checker.check(output.input.chain_id == proof.chain_id, "chain id")?;
+    checker.check(output.input.spec_id == chain_spec.spec_id(header.number, header.timestamp)?, "hardfork of the block")?;
+    checker.check(output.input.gas_price >= output.input.block_env.basefee, "gas price")?;
+    checker.check(U256::from(output.input.gas_limit) <= output.input.block_env.gas_limit, "gas limit")?;
     
     // verify db
-    let rpc_cache_dir = dirs_next::home_dir().expect("home dir not found").join(".securfi").join("cache").join("rpc");
-    let cache_path =  rpc_cache_dir.join(format!("{}", proof.chain_id)).join(format!("{}.json", proof.block_number));
-    let chain_spec = ChainSpec::mainnet();
-    let meta = BlockchainDbMeta {
-        chain_spec: chain_spec.clone(), // currently only supports mainnet and shanghai
-        header: header,
-    };
-    let rpc_db = JsonBlockCacheDB::new(&provider, meta, Some(cache_path));
-    let initial_balance = U256::ZERO;
 
+    // the slots the token deals wrote hold the dealt balances instead of the chain values
+    let dealt_storage = token_deal_storage(&rpc_db, &proof.deals)?;
     for (address, acc_storage) in output.input.db.accounts.iter() {
         let address = address.clone();
         if address == DEFAULT_CONTRACT_ADDRESS {
-            if acc_storage.info.balance != initial_balance {
-                bail!("balance is not correct")
-            }
-            if acc_storage.info.code_hash != proof.poc_code_hash {
-                bail!("code hash is not correct")
-            }
+            checker.check(acc_storage.info.balance == proof.initial_balance, "balance of the poc contract")?;
+            checker.check(acc_storage.info.code_hash == proof.poc_code_hash, "code hash of the poc contract")?;
             continue;
         }
         if address == DEFAULT_CALLER {
-            if acc_storage.info.balance != initial_balance {
-                bail!("balance is not correct")
-            }
+            checker.check(acc_storage.info.balance == output.input.caller_balance(), "balance of the caller")?;
             continue;
         }
-        let info = rpc_db.basic_ref(address)?.unwrap();
-        if info != acc_storage.info {
-            bail!("account info is not correct")
+        let mut info = rpc_db.basic_ref(address)?.unwrap_or_default();
+        if let Some(balance) = ether_balance(&proof.deals, address) {
+            info.balance = balance;
         }
+        checker.check(info == acc_storage.info, format!("account info of {}", address))?;
         for (key, value) in acc_storage.storage.iter() {
-            let slot = rpc_db.storage_ref(address, *key)?;
-            if slot != *value {
-                bail!("storage slot is not correct")
-            }
+            let slot = match dealt_storage.get(&address).and_then(|storage| storage.get(key)) {
+                Some(dealt) => *dealt,
+                None => rpc_db.storage_ref(address, *key)?,
+            };
+            checker.check(slot == *value, format!("storage slot {} of {}", key, address))?;
         }
     }
 
     for (block_number, block_hash) in output.input.db.block_hashes.iter() {
-        if *block_hash != rpc_db.block_hash_ref(U256::from(*block_number))? {
-            bail!("block hash is not correct")
+        let expected = rpc_db.block_hash_ref(U256::from(*block_number))?;
+        checker.check(*block_hash == expected, format!("block hash of {}", block_number))?;
+    }
+
+    // the committed state must be all the exploit reads, the guest doesn't commit the poc
+    // code so this needs the source
+    let mut trace = None;
+    if let Some(code) = poc_code {
+        let mut input = output.input.clone();
+        if let Some(poc) = input.db.accounts.get_mut(&DEFAULT_CONTRACT_ADDRESS) {
+            poc.info.code = Some(code);
         }
+        let replayed = match resim_exploit(&input) {
+            Ok((replay, tracer)) => {
+                trace = Some(tracer.calls);
+                // all of the journal must follow from the committed input, not only the state
+                exploit_outcome(&output.input, &replay.result) == Ok(output.revert.clone())
+                    && replay.state == output.state
+                    && replay.result.gas_used() == output.gas_used
+                    && gas_refunded(&replay.result) == output.gas_refunded
+                    && replay.result.logs() == output.logs.as_slice()
+            }
+            Err(_) => false,
+        };
+        checker.check(replayed, "re-simulation over the committed state")?;
+    } else {
+        checker.skip("re-simulation over the committed state", "it needs the poc code, pass --poc");
+    }
+    let revert = output.revert.as_ref().map(|revert| decode_revert(revert, None));
+    // a proof of a revert is checked even without --expect-revert, it proves nothing else
+    if output.input.expect_revert || args.expect_revert.is_some() {
+        checker.check(
+            revert_as_expected(output.input.expect_revert, revert.as_deref(), args.expect_revert.as_ref()),
+            "revert of the exploit",
+        )?;
     }
 
-    let state_diff = compute_state_diff(&output.state, &output.input.db);
+    let wrapped_native = if args.combine_wrapped_native {
+        args.wrapped_native.or(chain_spec.wrapped_native())
+    } else {
+        None
+    };
+    // the asset change queries the token balances with evm calls, skip it when not wanted
+    let (state_diff, asset_change, net_gain) = if args.only_state_diff {
+        (Some(compute_state_diff(&output.state, &output.input.db)), None, None)
+    } else {
+        let ExploitSummary { state_diff, asset_change, net_gain, .. } = summarize_exploit(&output, wrapped_native)?;
+        ((!args.only_asset_change).then_some(state_diff), Some(asset_change), Some(net_gain))
+    };
 
     let accounts: Vec<Address> = output.input.db.accounts.keys().cloned().collect();
-
-    let asset_change = compute_asset_change(&accounts, &output.input.db, output.state)?;
+    let slot_labels = match &state_diff {
+        Some(state_diff) if args.label_slots => label_slots(state_diff, &accounts),
+        _ => BTreeMap::new(),
+    };
+    for expectation in args.expect_gain.iter() {
+        checker.check(
+            expectation.check(DEFAULT_CONTRACT_ADDRESS, asset_change.as_deref().unwrap_or_default()),
+            format!("gain of the poc contract in {} {:?} {}", expectation.token, expectation.comparison, expectation.amount),
+        )?;
+    }
+    let skipped_checks = checker.finish()?;
+    if args.explain {
+        // the json keeps the raw amounts, the human output scales them by the decimals
+        for gain in net_gain.iter().flatten() {
+            let (decimals, symbol) = if gain.token == Address::ZERO {
+                (18, "ETH".to_string())
+            } else {
+                token_metadata(&rpc_db, gain.token).unwrap_or((0, gain.token.to_string()))
+            };
+            eprintln!("{} gained {} {}", gain.address, format_token_amount(gain.amount, decimals), symbol);
+        }
+        // the caller pays the gas, not the poc contract, weigh it against the ether gained
+        let gas_fee = I256::try_from(output.input.gas_price * U256::from(output.gas_used))?;
+        eprintln!("the exploit tx paid {} ETH for gas", format_token_amount(gas_fee, 18));
+    }
+    let warnings = match &asset_change {
+        Some(asset_change) => reconcile_transfers(&accounts, asset_change, &output.logs),
+        None => Vec::new(),
+    };
+    for warning in warnings.iter() {
+        eprintln!("Warning: {}", warning);
+    }
 
     Ok(VerifyResult {
+        proven: proof.receipt.is_some(),
+        revert: revert,
+        calldata: (output.input.calldata != CALL_EXPLOIT_DATA).then(|| output.input.calldata.clone()),
         version: proof.version,
         image_id: proof.image_id,
         chain_id: proof.chain_id,
         spec_id: proof.spec_id,
         block_number: proof.block_number,
+        env_block_number: (env_block_number != proof.block_number).then_some(env_block_number),
         poc_code_hash: proof.poc_code_hash,
         deals: proof.deals,
+        initial_balance: proof.initial_balance,
+        setup_txs: output.input.setup_txs.clone(),
         gas_used: output.gas_used,
+        gas_refunded: output.gas_refunded,
+        gas_price: output.input.gas_price,
+        coinbase: (coinbase != header.author).then_some(coinbase),
+        gas_fee: output.input.gas_price * U256::from(output.gas_used),
+        stats: compute_exploit_stats(&output, trace.as_deref()),
         state_diff: state_diff,
+        slot_labels: slot_labels,
         asset_change: asset_change,
+        net_gain: net_gain,
+        warnings: warnings,
+        skipped_checks: skipped_checks,
     })
 }
 
 
 impl VerifyArgs {
-    pub async fn run(self) -> Result<()> {
-        let proof = Proof::load(self.path)?;
-        let result = verify(proof, self.rpc_url).await?;
+    pub async fn run(mut self) -> Result<()> {
+        let proof = Proof::load(&mut self.path)?;
+        let result = verify(proof, &self).await?;
+        write_result(&result, self.output, self.pretty)
+    }
+}
 
-        serde_json::to_writer(self.output, &result)?;
-        Ok(())
+/// Writes the result as json. serde_json writes it as it walks it, the small writes are
+/// buffered. The result itself is built in memory first, its sections depend on each other.
+fn write_result<W: Write>(result: &VerifyResult, output: W, pretty: bool) -> Result<()> {
+    let mut output = BufWriter::new(output);
+    if pretty {
+        serde_json::to_writer_pretty(&mut output, result)?;
+    } else {
+        serde_json::to_writer(&mut output, result)?;
+    }
+    output.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chains_evm_core::state_diff::{AccountDiff, ChangedType, Delta};
+
+    #[test]
+    fn large_result_parses_back_in_both_formats() {
+        let mut state_diff = StateDiff::default();
+        for i in 0..1000u64 {
+            let mut account = AccountDiff::default();
+            account.balance = Delta::Changed(ChangedType { from: U256::from(i), to: U256::from(i + 1) });
+            for slot in 0..20u64 {
+                account.storage.insert(U256::from(slot), Delta::Added(U256::from(i * slot)));
+            }
+            state_diff.insert(Address::from_word(U256::from(i).into()), account);
+        }
+        let result = VerifyResult {
+            proven: true,
+            state_diff: Some(state_diff),
+            asset_change: Some(vec![AssetChange::default(); 100]),
+            ..Default::default()
+        };
+        let expected = serde_json::to_value(&result).unwrap();
+        for pretty in [false, true] {
+            let mut output = Vec::new();
+            write_result(&result, &mut output, pretty).unwrap();
+            let parsed: VerifyResult = serde_json::from_slice(&output).unwrap();
+            assert_eq!(serde_json::to_value(&parsed).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn skipped_checks_are_reported_and_failures_still_fail() {
+        let mut checker = Checker::new(true);
+        checker.check(true, "receipt").unwrap();
+        checker.skip("re-simulation over the committed state", "pass --poc");
+        assert_eq!(checker.finish().unwrap(), vec!["re-simulation over the committed state".to_string()]);
+
+        let mut checker = Checker::new(true);
+        checker.skip("poc source", "pass --poc");
+        checker.check(false, "block env").unwrap();
+        assert!(checker.finish().is_err());
+    }
+
+    #[test]
+    fn env_block_must_follow_the_state_block_within_256_blocks() {
+        assert!(env_block_in_window(100, 100));
+        assert!(env_block_in_window(100, 356));
+        assert!(!env_block_in_window(100, 357));
+        assert!(!env_block_in_window(100, 99));
+        assert!(!env_block_in_window(u64::MAX, 0));
+    }
+
+    #[test]
+    fn proof_of_a_revert_checks_its_reason() {
+        let reason = Some("revert: insufficient balance");
+        assert!(revert_as_expected(true, reason, None));
+        assert!(revert_as_expected(true, reason, Some(&None)));
+        assert!(revert_as_expected(true, reason, Some(&Some("revert: insufficient balance".to_string()))));
+        assert!(!revert_as_expected(true, reason, Some(&Some("revert: paused".to_string()))));
+        // a successful exploit doesn't prove a revert
+        assert!(!revert_as_expected(false, None, Some(&None)));
+        assert!(!revert_as_expected(true, None, None));
+    }
+
+    fn proof(image_id: ImageId) -> Proof {
+        Proof {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            image_id: image_id.to_hex(),
+            chain_id: 1,
+            spec_id: "SHANGHAI".to_string(),
+            block_number: 19_000_000,
+            env_block_number: 19_000_000,
+            poc_code_hash: B256::repeat_byte(1),
+            deals: Vec::new(),
+            initial_balance: U256::ZERO,
+            solc_version: "0.8.20".to_string(),
+            evm_version: "shanghai".to_string(),
+            receipt: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn proof_of_an_unknown_guest_is_rejected() {
+        let args = VerifyArgs::try_parse_from(["verify", "-"]).unwrap();
+        let err = verify(proof(ImageId::new([7; 8])), &args).await.unwrap_err();
+        assert!(err.to_string().contains(&format!("unknown guest {}", ImageId::new([7; 8]).to_hex())), "{}", err);
+
+        // the proof of this guest gets past the check, to the missing receipt
+        let err = verify(proof(crate::proof::EXPLOIT_IMAGE_ID), &args).await.unwrap_err();
+        assert!(err.to_string().starts_with("the proof has no receipt"), "{}", err);
     }
-}
\ No newline at end of file
+}
@@ -1,6 +1,6 @@
 #![no_main]
 
-use bridge::{ExploitInput, ExploitOutput, sim_exploit, DEFAULT_CONTRACT_ADDRESS};
+use bridge::{exploit_outcome, gas_refunded, ExploitInput, ExploitOutput, sim_exploit, DEFAULT_CONTRACT_ADDRESS};
 use risc0_zkvm::guest::env;
 
 risc0_zkvm::guest::entry!(main);
@@ -8,15 +8,19 @@ risc0_zkvm::guest::entry!(main);
 pub fn main() {
     let input: ExploitInput = env::read();
     let result_and_state = sim_exploit(&input);
-    if !result_and_state.result.is_success() {
+    let Ok(revert) = exploit_outcome(&input, &result_and_state.result) else {
         panic!()
-    }
+    };
 
     let gas_used = result_and_state.result.gas_used();
+    let gas_refunded = gas_refunded(&result_and_state.result);
     let mut output = ExploitOutput {
         input: input,
         gas_used: gas_used,
+        gas_refunded: gas_refunded,
+        logs: result_and_state.result.logs().to_vec(),
         state: result_and_state.state,
+        revert: revert,
     };
     let poc_contract_info = output.input.db.accounts.get_mut(&DEFAULT_CONTRACT_ADDRESS).unwrap();
     poc_contract_info.info.code = None;